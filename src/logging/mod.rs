@@ -5,19 +5,22 @@ use std::{
     time::Duration,
 };
 
+use chrono::Utc;
 use futures::stream;
 use influxdb2::{
     models::{data_point::DataPointBuilder, DataPoint},
     Client,
 };
 use nvml_wrapper::Nvml;
+use serde::Serialize;
 use sysinfo::System;
 use tokio::runtime::Handle;
 mod error;
 pub use error::*;
 use uuid::Uuid;
 
-use crate::protocol::state::get_node_name;
+use crate::models::node_info::{format_cuda_version, GpuDevice, GpuInfo};
+use crate::protocol::state::{get_node_name, is_paused};
 
 pub mod logger;
 
@@ -39,7 +42,6 @@ pub(crate) fn setup_influx_logging(tokio_handle: Handle) -> Result<(), Error> {
             client,
             tokio_handle,
         });
-        start_load_logging();
     }
     Ok(())
 }
@@ -67,61 +69,159 @@ pub(crate) fn log_influx(data: Vec<DataPointBuilder>) {
     }
 }
 
-fn start_load_logging() {
+/// Describes the NVIDIA GPUs visible through NVML for the node info handshake.
+pub(crate) fn gpu_inventory() -> Result<GpuInfo, Error> {
+    let nvml = Nvml::init()?;
+    let mut devices = vec![];
+
+    for i in 0..nvml.device_count()? {
+        let device = nvml.device_by_index(i)?;
+        devices.push(GpuDevice {
+            index: i,
+            name: device.name()?,
+            uuid: device.uuid().ok(),
+            memory_total_bytes: device.memory_info()?.total,
+        });
+    }
+
+    Ok(GpuInfo {
+        driver_version: nvml.sys_driver_version().ok(),
+        cuda_version: nvml.sys_cuda_driver_version().ok().map(format_cuda_version),
+        devices,
+    })
+}
+
+/// The latest GPU, CPU and memory sample, reported by `STATUS`.
+static LATEST_LOAD: LazyLock<Mutex<Option<LoadSample>>> = LazyLock::new(|| Mutex::new(None));
+
+#[derive(Clone, Debug, Serialize)]
+pub struct LoadSample {
+    pub sampled_at: String,
+    pub gpus: Vec<GpuSample>,
+    pub cpu_usage: Vec<f32>,
+    pub memory: MemorySample,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct GpuSample {
+    pub index: u32,
+    pub memory_used: u64,
+    pub memory_free: u64,
+    pub memory_total: u64,
+    pub power_limit: u32,
+    pub encoder_util: u32,
+    pub sampling_period: u32,
+    pub energy_consumption: u64,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct MemorySample {
+    pub free: u64,
+    pub used: u64,
+    pub total: u64,
+    pub swap_free: u64,
+    pub swap_used: u64,
+    pub swap_total: u64,
+}
+
+pub(crate) fn latest_load() -> Option<LoadSample> {
+    LATEST_LOAD.lock().ok().and_then(|sample| sample.clone())
+}
+
+/// Samples GPU, CPU and memory usage every 5 seconds. The latest sample is
+/// kept for `STATUS` and, when InfluxDB is configured, written there.
+pub(crate) fn start_load_logging() {
     let _ = thread::Builder::new()
         .name("influx_logging".to_string())
-        .spawn(move || -> Result<(), Error> {
-            let nvml = Nvml::init()?;
+        .spawn(move || {
+            // Nodes without NVIDIA GPUs still report CPU and memory.
+            let nvml = Nvml::init().ok();
             let mut system = System::new_all();
-            let total_gpus = nvml.device_count()?;
 
             loop {
                 sleep(Duration::from_secs(5));
 
-                if get_node_name().eq("Unknown") {
-                    continue;
-                }
-
-                let mut data_points = vec![];
-
-                for i in 0..total_gpus {
-                    let device = nvml.device_by_index(i)?;
-                    let power_limit = device.enforced_power_limit()?; // 275k milliwatts on my system
-                    let encoder_util = device.encoder_utilization()?; // Currently 0 on my system; Not encoding anything
-                    let memory_info = device.memory_info()?; // Currently 1.63/6.37 GB used on my system
-
-                    data_points.extend([DataPoint::builder("gpu")
-                        .tag("index", i.to_string())
-                        .field("memory_used", memory_info.used as f64)
-                        .field("memory_free", memory_info.free as f64)
-                        .field("memory_total", memory_info.total as f64)
-                        .field("power_limit", power_limit as f64)
-                        .field("encoder_util", encoder_util.utilization as f64)
-                        .field("sampling_period", encoder_util.sampling_period as f64)
-                        .field(
-                            "energy_consumption",
-                            device.total_energy_consumption()? as f64,
-                        )]);
+                let sample = LoadSample {
+                    sampled_at: Utc::now().to_rfc3339(),
+                    gpus: nvml
+                        .as_ref()
+                        .and_then(|nvml| sample_gpus(nvml).ok())
+                        .unwrap_or_default(),
+                    cpu_usage: system.cpus().iter().map(|cpu| cpu.cpu_usage()).collect(),
+                    memory: MemorySample {
+                        free: system.free_memory(),
+                        used: system.used_memory(),
+                        total: system.total_memory(),
+                        swap_free: system.free_swap(),
+                        swap_used: system.used_swap(),
+                        swap_total: system.total_swap(),
+                    },
+                };
+                if let Ok(mut latest) = LATEST_LOAD.lock() {
+                    *latest = Some(sample.clone());
                 }
 
-                for cpu in system.cpus() {
-                    data_points
-                        .push(DataPoint::builder("cpu").field("usage", cpu.cpu_usage() as f64));
+                if !get_node_name().eq("Unknown") {
+                    log_influx(load_data_points(&sample));
                 }
 
-                data_points.push(
-                    DataPoint::builder("memory")
-                        .field("free", system.free_memory() as f64)
-                        .field("used", system.used_memory() as f64)
-                        .field("total", system.total_memory() as f64)
-                        .field("swap_free", system.free_swap() as f64)
-                        .field("swap_used", system.used_swap() as f64)
-                        .field("swap_total", system.total_swap() as f64),
-                );
-
-                log_influx(data_points);
-
                 system.refresh_all();
             }
         });
 }
+
+fn sample_gpus(nvml: &Nvml) -> Result<Vec<GpuSample>, Error> {
+    let mut gpus = vec![];
+    for i in 0..nvml.device_count()? {
+        let device = nvml.device_by_index(i)?;
+        let encoder_util = device.encoder_utilization()?;
+        let memory_info = device.memory_info()?;
+        gpus.push(GpuSample {
+            index: i,
+            memory_used: memory_info.used,
+            memory_free: memory_info.free,
+            memory_total: memory_info.total,
+            power_limit: device.enforced_power_limit()?,
+            encoder_util: encoder_util.utilization,
+            sampling_period: encoder_util.sampling_period,
+            energy_consumption: device.total_energy_consumption()?,
+        });
+    }
+    Ok(gpus)
+}
+
+fn load_data_points(sample: &LoadSample) -> Vec<DataPointBuilder> {
+    let mut data_points = vec![];
+
+    for gpu in &sample.gpus {
+        data_points.push(
+            DataPoint::builder("gpu")
+                .tag("index", gpu.index.to_string())
+                .field("memory_used", gpu.memory_used as f64)
+                .field("memory_free", gpu.memory_free as f64)
+                .field("memory_total", gpu.memory_total as f64)
+                .field("power_limit", gpu.power_limit as f64)
+                .field("encoder_util", gpu.encoder_util as f64)
+                .field("sampling_period", gpu.sampling_period as f64)
+                .field("energy_consumption", gpu.energy_consumption as f64),
+        );
+    }
+
+    for usage in &sample.cpu_usage {
+        data_points.push(DataPoint::builder("cpu").field("usage", *usage as f64));
+    }
+
+    data_points.push(
+        DataPoint::builder("memory")
+            .field("free", sample.memory.free as f64)
+            .field("used", sample.memory.used as f64)
+            .field("total", sample.memory.total as f64)
+            .field("swap_free", sample.memory.swap_free as f64)
+            .field("swap_used", sample.memory.swap_used as f64)
+            .field("swap_total", sample.memory.swap_total as f64),
+    );
+
+    data_points.push(DataPoint::builder("node").field("paused", is_paused()));
+
+    data_points
+}
@@ -1,13 +1,25 @@
 use dotenv::dotenv;
-use log::{error, warn};
+use log::{error, info, warn};
 use logging::logger::init_logging;
-use logging::setup_influx_logging;
-use protocol::backend::{configure_backend_runtime, configure_backend_runtime_blocking};
+use logging::{setup_influx_logging, start_load_logging};
+use protocol::auth::AuthError;
+use protocol::backend::{configure_backend_runtime, ensure_backend_runtime};
+use protocol::cluster::{load_clusters, ClusterConfig};
 use protocol::connection::run_protocol;
-use protocol::state::{get_shutdown, set_reboot};
-use std::thread::{sleep, spawn};
-use std::time::Duration;
+use protocol::control_auth::get_control_signing;
+use protocol::drain::{drain_deadline, drain_on_signals, drain_timeout, DRAIN_TIMEOUT_EXIT_CODE};
+use protocol::frame::max_frame_bytes;
+use protocol::mux::{mux_enabled, ClusterSessions};
+use protocol::reconnect::{FailureKind, ReconnectPolicy, Reconnector};
+use protocol::self_update::{
+    confirm_deadline, exec_node, inherited_nonce, restart_requested, resume_update,
+    rollback_update, update_pending, UpdateConfig,
+};
+use protocol::state::{get_shutdown, mark_started, set_reboot, shutdown_requested};
+use protocol::streaming::StreamConfig;
+use std::sync::Arc;
 use tokio::runtime::Handle;
+use tokio::time::sleep;
 
 mod logging;
 mod messages;
@@ -20,42 +32,169 @@ async fn main() -> anyhow::Result<()> {
     let _ = init_logging();
     let _ = dotenv();
     let _ = setup_influx_logging(Handle::current());
+    start_load_logging();
+    mark_started();
 
     // Initialize the selected inference backend.
     configure_backend_runtime().await?;
 
-    let concurrent = std::env::var("CONCURRENT_REQUESTS")
-        .expect("CONCURRENT_REQUESTS")
-        .parse::<usize>()?;
-    let nonce = rand::random::<u64>();
-    let reconnect_secs = 10;
-    let mut handles = Vec::with_capacity(concurrent);
-    for _ in 0..concurrent {
-        let movable_nonce = nonce;
-        handles.push(spawn(move || loop {
-            if let Err(e) = configure_backend_runtime_blocking() {
-                error!("Failed to ensure inference backend before reconnect: {}", e);
-                warn!("Waiting {}s before reconnecting", reconnect_secs);
-                sleep(Duration::from_secs(reconnect_secs));
-                continue;
-            }
+    let clusters = load_clusters()?;
+    // A restarted node keeps its nonce, so HiveCore sees the same instance.
+    let nonce = inherited_nonce().unwrap_or_else(rand::random::<u64>);
+    resume_update(nonce).await?;
+    let reconnect_policy = ReconnectPolicy::from_env()?;
+    let drain_timeout = drain_timeout()?;
+    get_control_signing()?;
+    max_frame_bytes()?;
+    let stream_config = StreamConfig::from_env()?;
+    UpdateConfig::from_env()?;
+    let mut handles = vec![];
+    for cluster in clusters {
+        info!(
+            "Serving cluster {} with {} connections",
+            cluster.name, cluster.concurrency
+        );
+        let cluster = Arc::new(cluster);
+        let sessions = Arc::new(ClusterSessions::new(mux_enabled()));
+        for slot in 0..cluster.concurrency {
+            let movable_nonce = nonce;
+            let cluster = cluster.clone();
+            let sessions = sessions.clone();
+            let policy = reconnect_policy.clone();
+            let stream_config = stream_config.clone();
+            handles.push(tokio::spawn(async move {
+                run_connection_slot(
+                    &cluster,
+                    &sessions,
+                    slot,
+                    movable_nonce,
+                    policy,
+                    stream_config,
+                )
+                .await
+            }));
+        }
+    }
+
+    tokio::spawn(async {
+        if let Err(e) = drain_on_signals().await {
+            error!("Failed to listen for shutdown signals: {}", e);
+        }
+    });
 
-            if let Err(e) = run_protocol(movable_nonce) {
-                error!("Connection to proxy ended: {}", e);
-                warn!("Waiting {}s before reconnecting", reconnect_secs);
+    // wait for all connections to finish, or for the drain deadline
+    let connections = async {
+        let mut refused = None;
+        for h in handles {
+            if let Ok(Some(auth_error)) = h.await {
+                refused = Some(auth_error);
             }
-            if get_shutdown() {
-                break;
+        }
+        refused
+    };
+    tokio::select! {
+        refused = connections => {
+            // Only stop the node for a permanent refusal once every cluster
+            // has stopped; a shutdown that ended the others exits as usual.
+            if let Some(auth_error) = refused.filter(|_| !get_shutdown()) {
+                if update_pending() {
+                    return Err(rollback_update(&auth_error.to_string(), nonce));
+                }
+                error!("Every HiveCore cluster has stopped, exiting");
+                std::process::exit(auth_error.exit_code());
             }
-            set_reboot(false);
-            sleep(Duration::from_secs(reconnect_secs));
-        }));
+        }
+        _ = drain_deadline(drain_timeout) => {
+            error!("Jobs were still running when the drain deadline passed");
+            if !restart_requested() {
+                std::process::exit(DRAIN_TIMEOUT_EXIT_CODE);
+            }
+        }
+        rollback = confirm_deadline(nonce) => return rollback,
     }
 
-    // wait for all threads to finish
-    for h in handles {
-        let _ = h.join();
+    if restart_requested() {
+        return Err(exec_node(nonce));
     }
-
+    info!("HiveNode drained, exiting");
     Ok(())
 }
+
+/// Keeps one connection to the cluster's HiveCore alive, reconnecting with
+/// backoff until shutdown. While the cluster multiplexes, only slot 0 connects.
+/// A permanent refusal stops the cluster's slots and is returned, so the
+/// other clusters keep running.
+async fn run_connection_slot(
+    cluster: &Arc<ClusterConfig>,
+    sessions: &ClusterSessions,
+    slot: usize,
+    nonce: u64,
+    policy: ReconnectPolicy,
+    stream_config: StreamConfig,
+) -> Option<AuthError> {
+    let mut reconnector = Reconnector::new(policy);
+    loop {
+        if slot > 0 {
+            tokio::select! {
+                _ = sessions.wait_for_per_connection() => {}
+                _ = shutdown_requested() => break,
+            }
+        }
+        if sessions.is_stopped() {
+            break;
+        }
+
+        if let Err(e) = ensure_backend_runtime().await {
+            error!("Failed to ensure inference backend before reconnect: {}", e);
+            let delay = reconnector.on_failure(FailureKind::Network);
+            warn!("Waiting {:.1}s before reconnecting", delay.as_secs_f64());
+            tokio::select! {
+                _ = sleep(delay) => {}
+                _ = shutdown_requested() => break,
+            }
+            reconnector.on_attempt();
+            continue;
+        }
+
+        let delay = match run_protocol(cluster, sessions, slot, nonce, &stream_config, || {
+            reconnector.on_connected()
+        })
+        .await
+        {
+            Ok(()) => reconnector.on_session_end(),
+            Err(e) => match e.downcast_ref::<AuthError>() {
+                // A freshly installed binary that HiveCore refuses is rolled
+                // back once no cluster is left. Transient failures back off
+                // as usual, and `confirm_deadline` bounds how long the update
+                // waits.
+                Some(auth_error) if auth_error.is_permanent() => {
+                    error!(
+                        "HiveCore of cluster {} refused this node permanently, stopping its connections: {}",
+                        cluster.name, auth_error
+                    );
+                    sessions.stop();
+                    return Some(auth_error.clone());
+                }
+                Some(_) => {
+                    error!("Connection to proxy ended: {:#}", e);
+                    reconnector.on_failure(FailureKind::Auth)
+                }
+                None => {
+                    error!("Connection to proxy ended: {:#}", e);
+                    reconnector.on_failure(FailureKind::Network)
+                }
+            },
+        };
+        if get_shutdown() || sessions.is_stopped() {
+            break;
+        }
+        set_reboot(false);
+        warn!("Waiting {:.1}s before reconnecting", delay.as_secs_f64());
+        tokio::select! {
+            _ = sleep(delay) => {}
+            _ = shutdown_requested() => break,
+        }
+        reconnector.on_attempt();
+    }
+    None
+}
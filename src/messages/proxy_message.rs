@@ -1,85 +1,222 @@
-use std::collections::HashMap;
+use std::borrow::Cow;
+use std::fmt::{Display, Formatter};
 
-use serde::Serialize;
+use httparse::Status;
+use serde::{de::DeserializeOwned, Serialize};
 use serde_json::Value;
 
-#[derive(Debug, Serialize)]
+/// Most header fields a message may carry.
+const MAX_HEADERS: usize = 100;
+
+/// Header fields in the order they arrived. Names keep their spelling but are
+/// matched without regard to case, and a name may appear more than once.
+#[derive(Clone, Debug, Default, Eq, PartialEq, Serialize)]
+pub struct Headers(Vec<(String, String)>);
+
+impl Headers {
+    /// The first value of `name`.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value)
+    }
+
+    /// Every value of `name`, in order.
+    pub fn get_all<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> {
+        self.iter()
+            .filter(move |(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value)
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.get(name).is_some()
+    }
+
+    pub fn append(&mut self, name: impl Into<String>, value: impl Into<String>) {
+        self.0.push((name.into(), value.into()));
+    }
+
+    /// Replaces every value of `name` with `value`.
+    pub fn set(&mut self, name: impl Into<String>, value: impl Into<String>) {
+        let name = name.into();
+        self.remove(&name);
+        self.append(name, value);
+    }
+
+    pub fn remove(&mut self, name: &str) {
+        self.0.retain(|(key, _)| !key.eq_ignore_ascii_case(name));
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.0
+            .iter()
+            .map(|(key, value)| (key.as_str(), value.as_str()))
+    }
+}
+
+impl<K: Into<String>, V: Into<String>> FromIterator<(K, V)> for Headers {
+    fn from_iter<I: IntoIterator<Item = (K, V)>>(iter: I) -> Self {
+        Self(
+            iter.into_iter()
+                .map(|(key, value)| (key.into(), value.into()))
+                .collect(),
+        )
+    }
+}
+
+/// Why a frame is not a valid message.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum ParseError {
+    /// The first line is not `<method> <uri> <protocol>`.
+    RequestLine(String),
+    Headers(httparse::Error),
+    /// A `Content-Length` that is not a number, or several that disagree.
+    ContentLength(String),
+    /// A `Transfer-Encoding` other than `chunked`.
+    TransferEncoding(String),
+    /// Malformed chunked framing.
+    Chunk,
+    /// The frame ends before the body does.
+    Truncated,
+    /// The frame carries this many bytes after the end of the body.
+    TrailingBytes(usize),
+}
+
+impl Display for ParseError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::RequestLine(line) => write!(f, "Invalid request line `{line}`"),
+            Self::Headers(e) => write!(f, "Invalid headers: {e}"),
+            Self::ContentLength(value) => write!(f, "Invalid Content-Length `{value}`"),
+            Self::TransferEncoding(value) => write!(f, "Unsupported Transfer-Encoding `{value}`"),
+            Self::Chunk => f.write_str("Invalid chunked body"),
+            Self::Truncated => f.write_str("Message body is truncated"),
+            Self::TrailingBytes(n) => write!(f, "{n} bytes follow the message body"),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+#[derive(Eq, PartialEq, Serialize)]
 pub struct ProxyMessage {
     pub protocol: String,
     pub method: String,
     pub uri: String,
-    pub headers: HashMap<String, String>,
-    pub body: String,
+    pub headers: Headers,
+    /// The body exactly as it arrived, which need not be text.
+    pub body: Vec<u8>,
+}
+
+/// Shows the body as text, since most bodies are JSON.
+impl std::fmt::Debug for ProxyMessage {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ProxyMessage")
+            .field("protocol", &self.protocol)
+            .field("method", &self.method)
+            .field("uri", &self.uri)
+            .field("headers", &self.headers)
+            .field("body", &self.body_text())
+            .finish()
+    }
 }
 
-impl From<String> for ProxyMessage {
-    fn from(raw_request: String) -> Self {
-        let mut lines = raw_request.lines();
-        let request_line = lines.next().unwrap_or("");
-        let mut headers = HashMap::new();
-
-        // Parse method and URI
-        let mut request_parts = request_line.split_whitespace();
-        let method = request_parts.next().unwrap_or("").to_string();
-        let uri = request_parts.next().unwrap_or("").to_string();
-        let protocol = request_parts.next().unwrap_or("").to_string();
-
-        // Parse headers
-        for line in &mut lines {
-            if line.is_empty() {
-                // End of headers
-                break;
+impl ProxyMessage {
+    /// Parses one frame: a request line, header fields and the body. The
+    /// frame delimits the message, so the blank line after the headers may be
+    /// left out when there is no body. The body is `Content-Length` bytes when
+    /// that is given and the rest of the frame otherwise. A chunked body is
+    /// decoded, and its `Transfer-Encoding` replaced by the decoded length.
+    pub fn parse(frame: &[u8]) -> Result<Self, ParseError> {
+        let line_end = frame
+            .iter()
+            .position(|&byte| byte == b'\n')
+            .unwrap_or(frame.len());
+        let line = String::from_utf8_lossy(&frame[..line_end]);
+        let line = line.strip_suffix('\r').unwrap_or(&line);
+        let (method, uri, protocol) = match line.split_ascii_whitespace().collect::<Vec<_>>()[..] {
+            [method, uri, protocol] => (method, uri, protocol),
+            _ => return Err(ParseError::RequestLine(line.to_string())),
+        };
+
+        let rest = frame.get(line_end + 1..).unwrap_or_default();
+        let (mut headers, consumed) = parse_header_block(rest)?;
+        let rest = &rest[consumed..];
+
+        let body = if let Some(encoding) = transfer_encoding(&headers) {
+            if !encoding.eq_ignore_ascii_case("chunked") {
+                return Err(ParseError::TransferEncoding(encoding));
             }
-            let mut parts = line.splitn(2, ": ");
-            let name = parts.next().unwrap_or("");
-            let value = parts.next().unwrap_or("");
-            headers.insert(name.to_string(), value.to_string());
-        }
+            let (body, trailers) = decode_chunked(rest)?;
+            headers.remove("Transfer-Encoding");
+            headers.set("Content-Length", body.len().to_string());
+            headers.0.extend(trailers.0);
+            body
+        } else if let Some(length) = content_length(&headers)? {
+            match rest.len().cmp(&length) {
+                std::cmp::Ordering::Less => return Err(ParseError::Truncated),
+                std::cmp::Ordering::Greater => {
+                    return Err(ParseError::TrailingBytes(rest.len() - length))
+                }
+                std::cmp::Ordering::Equal => rest.to_vec(),
+            }
+        } else {
+            rest.to_vec()
+        };
 
-        // Read the body
-        let body = lines.collect::<Vec<&str>>().join("\n");
-        Self {
-            protocol,
-            method,
-            uri,
+        Ok(Self {
+            protocol: protocol.to_string(),
+            method: method.to_string(),
+            uri: uri.to_string(),
             headers,
             body,
+        })
+    }
+
+    /// The message in wire format, the inverse of [`ProxyMessage::parse`].
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = format!("{} {} {}\r\n", self.method, self.uri, self.protocol).into_bytes();
+        for (name, value) in self.headers.iter() {
+            bytes.extend_from_slice(format!("{name}: {value}\r\n").as_bytes());
         }
+        bytes.extend_from_slice(b"\r\n");
+        bytes.extend_from_slice(&self.body);
+        bytes
     }
-}
 
-impl ProxyMessage {
     pub fn new_http_get(uri: &str) -> Self {
         Self {
             protocol: "HTTP/1.1".into(),
             method: "GET".into(),
             uri: uri.into(),
-            headers: HashMap::new(),
-            body: "\n".into(),
+            headers: Headers::default(),
+            body: Vec::new(),
         }
     }
 
-    pub fn worker_command(&self) -> Option<&str> {
-        fn is_supported_worker_command(command: &str) -> bool {
-            matches!(command, "REBOOT" | "SHUTDOWN" | "UPDATE" | "UPDATE_OLLAMA")
-        }
+    /// The body as text, with invalid UTF-8 replaced.
+    pub fn body_text(&self) -> Cow<'_, str> {
+        String::from_utf8_lossy(&self.body)
+    }
 
-        match (
-            self.protocol.as_str(),
-            self.method.as_str(),
-            self.uri.as_str(),
-        ) {
-            ("HIVE", command, _) if is_supported_worker_command(command) => Some(command),
-            ("HTTP/1.1", "POST", "/worker/command") => {
-                let command = self.body.trim();
-                if is_supported_worker_command(command) {
-                    Some(command)
-                } else {
-                    None
-                }
-            }
-            _ => None,
-        }
+    /// Deserializes the body as JSON.
+    pub fn json<T: DeserializeOwned>(&self) -> serde_json::Result<T> {
+        serde_json::from_slice(&self.body)
+    }
+
+    /// Replaces the body with `value` as JSON and sets the headers that
+    /// describe it.
+    pub fn set_json<T: Serialize + ?Sized>(&mut self, value: &T) -> serde_json::Result<()> {
+        self.body = serde_json::to_vec(value)?;
+        self.headers.set("Content-Type", "application/json");
+        self.headers
+            .set("Content-Length", self.body.len().to_string());
+        Ok(())
+    }
+
+    /// Looks up a header by name, ignoring case.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name)
     }
 
     pub fn modifies_poll(&self) -> bool {
@@ -90,94 +227,218 @@ impl ProxyMessage {
         ) {
             ("HTTP/1.1", "POST", "/api/pull") => true,
             ("HTTP/1.1", "DELETE", "/api/delete") => true,
+            ("HTTP/1.1", "POST", "/api/copy") => true,
             ("HTTP/1.1", "GET", "/api/tags") => true,
             (_, _, _) => false,
         }
     }
 
     pub fn extract_model(&self) -> Option<String> {
-        // Remove any surrounding whitespace (including newlines).
-        let trimmed = self.body.trim();
-        if trimmed.is_empty() {
-            return None;
-        }
-
-        // Attempt to parse the trimmed string as JSON.
-        let json_value: Value = serde_json::from_str(trimmed).ok()?;
+        // Bodies that are empty or not JSON name no model.
+        let json_value: Value = self.json().ok()?;
 
         // If the JSON is an object and has a "model" key as a string, return it.
         json_value.get("model")?.as_str().map(String::from)
     }
 }
 
+/// Parses header fields up to the blank line, or to the end of `bytes` when
+/// there is none. Returns the fields and the number of bytes they took.
+fn parse_header_block(bytes: &[u8]) -> Result<(Headers, usize), ParseError> {
+    let mut fields = [httparse::EMPTY_HEADER; MAX_HEADERS];
+    let (consumed, parsed) = match httparse::parse_headers(bytes, &mut fields) {
+        Ok(Status::Complete(complete)) => complete,
+        Ok(Status::Partial) => return parse_unterminated_block(bytes),
+        Err(e) => return Err(ParseError::Headers(e)),
+    };
+    Ok((to_headers(parsed)?, consumed))
+}
+
+fn parse_unterminated_block(bytes: &[u8]) -> Result<(Headers, usize), ParseError> {
+    let mut terminated = bytes.to_vec();
+    terminated.extend_from_slice(b"\r\n\r\n");
+    let mut fields = [httparse::EMPTY_HEADER; MAX_HEADERS];
+    match httparse::parse_headers(&terminated, &mut fields) {
+        Ok(Status::Complete((_, parsed))) => Ok((to_headers(parsed)?, bytes.len())),
+        Ok(Status::Partial) => Err(ParseError::Headers(httparse::Error::NewLine)),
+        Err(e) => Err(ParseError::Headers(e)),
+    }
+}
+
+fn to_headers(parsed: &[httparse::Header<'_>]) -> Result<Headers, ParseError> {
+    parsed
+        .iter()
+        .map(|field| {
+            std::str::from_utf8(field.value)
+                .map(|value| (field.name, value))
+                .map_err(|_| ParseError::Headers(httparse::Error::HeaderValue))
+        })
+        .collect()
+}
+
+/// The last transfer coding, which is the one that frames the body.
+fn transfer_encoding(headers: &Headers) -> Option<String> {
+    headers
+        .get_all("Transfer-Encoding")
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .filter(|coding| !coding.is_empty())
+        .last()
+        .map(String::from)
+}
+
+fn content_length(headers: &Headers) -> Result<Option<usize>, ParseError> {
+    let mut length = None;
+    for value in headers
+        .get_all("Content-Length")
+        .flat_map(|value| value.split(','))
+    {
+        let parsed = value
+            .trim()
+            .parse::<usize>()
+            .map_err(|_| ParseError::ContentLength(value.trim().to_string()))?;
+        if length.is_some_and(|length| length != parsed) {
+            return Err(ParseError::ContentLength(value.trim().to_string()));
+        }
+        length = Some(parsed);
+    }
+    Ok(length)
+}
+
+/// Decodes a chunked body that must make up the rest of the frame. Returns
+/// the body and the trailer fields.
+fn decode_chunked(mut rest: &[u8]) -> Result<(Vec<u8>, Headers), ParseError> {
+    let mut body = Vec::new();
+    loop {
+        let (consumed, size) = match httparse::parse_chunk_size(rest) {
+            Ok(Status::Complete(chunk)) => chunk,
+            Ok(Status::Partial) => return Err(ParseError::Truncated),
+            Err(_) => return Err(ParseError::Chunk),
+        };
+        rest = &rest[consumed..];
+        if size == 0 {
+            break;
+        }
+        let size = usize::try_from(size).map_err(|_| ParseError::Chunk)?;
+        let chunk_end = size.checked_add(2).ok_or(ParseError::Chunk)?;
+        if rest.len() < chunk_end {
+            return Err(ParseError::Truncated);
+        }
+        if &rest[size..chunk_end] != b"\r\n" {
+            return Err(ParseError::Chunk);
+        }
+        body.extend_from_slice(&rest[..size]);
+        rest = &rest[chunk_end..];
+    }
+
+    let (trailers, consumed) = parse_header_block(rest)?;
+    match rest.len() - consumed {
+        0 => Ok((body, trailers)),
+        trailing => Err(ParseError::TrailingBytes(trailing)),
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use super::ProxyMessage;
+    use serde_json::json;
+
+    use super::{Headers, ParseError, ProxyMessage};
+
+    fn parse(raw: &str) -> Result<ProxyMessage, ParseError> {
+        ProxyMessage::parse(raw.as_bytes())
+    }
 
     #[test]
     fn parses_request_line_headers_and_body() {
-        let raw = "POST /api/generate HTTP/1.1\r\nContent-Type: application/json\r\nX-Test: yes\r\n\r\n{\"model\":\"llama3\"}".to_string();
-        let message = ProxyMessage::from(raw);
+        let raw = "POST /api/generate HTTP/1.1\r\nContent-Type: application/json\r\nX-Test: yes\r\n\r\n{\"model\":\"llama3\"}";
+        let message = parse(raw).unwrap();
 
         assert_eq!(message.method, "POST");
         assert_eq!(message.uri, "/api/generate");
         assert_eq!(message.protocol, "HTTP/1.1");
-        assert_eq!(
-            message.headers.get("Content-Type").map(String::as_str),
-            Some("application/json")
-        );
-        assert_eq!(message.body, "{\"model\":\"llama3\"}");
+        assert_eq!(message.header("content-type"), Some("application/json"));
+        assert_eq!(message.body, b"{\"model\":\"llama3\"}");
+
+        let bare = parse("PONG 7 HIVE\r\n").unwrap();
+        assert_eq!((bare.method.as_str(), bare.uri.as_str()), ("PONG", "7"));
+        assert_eq!(bare.headers, Headers::default());
+        assert!(bare.body.is_empty());
     }
 
     #[test]
-    fn extracts_model_from_json_body() {
-        let message = ProxyMessage {
-            protocol: "HTTP/1.1".into(),
-            method: "POST".into(),
-            uri: "/api/generate".into(),
-            headers: Default::default(),
-            body: "{\"model\":\"mistral\"}\n".into(),
-        };
+    fn keeps_repeated_headers_and_body_bytes() {
+        let raw = b"POST /api/chat HTTP/1.1\r\nAccept: a\r\nContent-Length: 10\r\naccept: b\r\n\r\nline\r\n\xffend";
+        let message = ProxyMessage::parse(raw).unwrap();
 
-        assert_eq!(message.extract_model().as_deref(), Some("mistral"));
+        assert_eq!(
+            message.headers.get_all("ACCEPT").collect::<Vec<_>>(),
+            ["a", "b"]
+        );
+        assert_eq!(message.body, b"line\r\n\xffend");
+        assert_eq!(message.to_bytes(), raw);
+        assert_eq!(ProxyMessage::parse(&message.to_bytes()).unwrap(), message);
     }
 
     #[test]
-    fn extracts_worker_command_from_http_request_body() {
-        let message = ProxyMessage {
-            protocol: "HTTP/1.1".into(),
-            method: "POST".into(),
-            uri: "/worker/command".into(),
-            headers: Default::default(),
-            body: " UPDATE \n".into(),
-        };
+    fn decodes_chunked_bodies() {
+        let raw = "POST /api/chat HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n4\r\n{\"a\"\r\n3;ext=1\r\n:1}\r\n0\r\nX-Trailer: t\r\n\r\n";
+        let message = parse(raw).unwrap();
 
-        assert_eq!(message.worker_command(), Some("UPDATE"));
+        assert_eq!(message.body, b"{\"a\":1}");
+        assert_eq!(
+            message.headers,
+            Headers::from_iter([("Content-Length", "7"), ("X-Trailer", "t")])
+        );
+        assert_eq!(
+            parse("POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nab"),
+            Err(ParseError::Truncated)
+        );
     }
 
     #[test]
-    fn extracts_update_ollama_worker_command_from_hive_method() {
-        let message = ProxyMessage {
-            protocol: "HIVE".into(),
-            method: "UPDATE_OLLAMA".into(),
-            uri: "/".into(),
-            headers: Default::default(),
-            body: String::new(),
-        };
-
-        assert_eq!(message.worker_command(), Some("UPDATE_OLLAMA"));
+    fn reports_malformed_messages() {
+        assert!(matches!(parse(""), Err(ParseError::RequestLine(_))));
+        assert!(matches!(
+            parse("GET /api/tags\r\n\r\n"),
+            Err(ParseError::RequestLine(_))
+        ));
+        assert!(matches!(
+            parse("GET / HTTP/1.1\r\nBad Header\r\n\r\n"),
+            Err(ParseError::Headers(_))
+        ));
+        assert_eq!(
+            parse("POST / HTTP/1.1\r\nContent-Length: 5\r\n\r\nab"),
+            Err(ParseError::Truncated)
+        );
+        assert_eq!(
+            parse("POST / HTTP/1.1\r\nContent-Length: 2\r\n\r\nabcd"),
+            Err(ParseError::TrailingBytes(2))
+        );
+        assert!(matches!(
+            parse("POST / HTTP/1.1\r\nContent-Length: 2\r\nContent-Length: 3\r\n\r\nab"),
+            Err(ParseError::ContentLength(_))
+        ));
+        assert!(matches!(
+            parse("POST / HTTP/1.1\r\nTransfer-Encoding: gzip\r\n\r\n"),
+            Err(ParseError::TransferEncoding(_))
+        ));
     }
 
     #[test]
-    fn extracts_worker_command_from_hive_message_method() {
-        let message = ProxyMessage {
-            protocol: "HIVE".into(),
-            method: "SHUTDOWN".into(),
-            uri: String::new(),
+    fn extracts_model_from_json_body() {
+        let mut message = ProxyMessage {
+            protocol: "HTTP/1.1".into(),
+            method: "POST".into(),
+            uri: "/api/generate".into(),
             headers: Default::default(),
-            body: String::new(),
+            body: b"{\"model\":\"mistral\"}\n".to_vec(),
         };
+        assert_eq!(message.extract_model().as_deref(), Some("mistral"));
 
-        assert_eq!(message.worker_command(), Some("SHUTDOWN"));
+        message.set_json(&json!({"prompt": "hi"})).unwrap();
+        assert_eq!(message.header("content-length"), Some("15"));
+        assert_eq!(message.extract_model(), None);
+        message.body = vec![0xff, b'{'];
+        assert_eq!(message.extract_model(), None);
     }
 }
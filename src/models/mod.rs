@@ -1,2 +1,4 @@
+pub mod node_info;
+pub mod node_status;
 pub mod poller;
 pub mod tags;
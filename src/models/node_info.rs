@@ -0,0 +1,188 @@
+use log::warn;
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use sysinfo::System;
+
+use crate::logging::gpu_inventory;
+use crate::protocol::backend::get_backend;
+use crate::protocol::docker::get_ollama_mode;
+use crate::protocol::lease::LEASE_FEATURE;
+use crate::protocol::mux::MUX_FEATURE;
+
+/// Protocol features this HiveNode build understands, advertised in `NODE-INFO`.
+pub const SUPPORTED_FEATURES: &[&str] = &[
+    "tls",
+    "challenge-auth",
+    "auth-result",
+    "node-info",
+    "heartbeat",
+    LEASE_FEATURE,
+    "pause",
+    "status",
+    "model-commands",
+    "control-envelope-v1",
+    "events",
+    "self-update",
+    "signed-control",
+    "protocol-error",
+];
+
+/// Hardware does not change while the process runs, so it is probed once and
+/// shared by every connection.
+static HARDWARE: Lazy<HardwareInfo> = Lazy::new(HardwareInfo::probe);
+
+/// Structured node description sent to HiveCore right after authentication.
+#[derive(Debug, Serialize)]
+pub struct NodeInfo {
+    pub node_version: String,
+    pub backend: String,
+    pub backend_version: String,
+    pub ollama_mode: Option<String>,
+    #[serde(flatten)]
+    pub hardware: HardwareInfo,
+    pub features: Vec<String>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct HardwareInfo {
+    pub hostname: Option<String>,
+    pub os: Option<String>,
+    pub cpu: CpuInfo,
+    pub memory: MemoryInfo,
+    pub gpu: Option<GpuInfo>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct CpuInfo {
+    pub model: String,
+    pub logical_cores: usize,
+    pub physical_cores: Option<usize>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct MemoryInfo {
+    pub total_bytes: u64,
+    pub swap_total_bytes: u64,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct GpuInfo {
+    pub driver_version: Option<String>,
+    pub cuda_version: Option<String>,
+    pub devices: Vec<GpuDevice>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct GpuDevice {
+    pub index: u32,
+    pub name: String,
+    pub uuid: Option<String>,
+    pub memory_total_bytes: u64,
+}
+
+impl NodeInfo {
+    /// `offer_mux` adds the `mux` feature, which only the connection that may
+    /// carry a cluster's multiplexed session advertises.
+    pub fn collect(backend_version: String, offer_mux: bool) -> Self {
+        let (backend, ollama_mode) = backend_kind();
+
+        Self {
+            node_version: env!("CARGO_PKG_VERSION").to_string(),
+            backend,
+            backend_version,
+            ollama_mode,
+            hardware: HARDWARE.clone(),
+            features: SUPPORTED_FEATURES
+                .iter()
+                .copied()
+                .chain(offer_mux.then_some(MUX_FEATURE))
+                .map(String::from)
+                .collect(),
+        }
+    }
+}
+
+/// The backend label and, for Ollama, how it is run.
+pub fn backend_kind() -> (String, Option<String>) {
+    let backend = get_backend()
+        .map(|backend| backend.label())
+        .unwrap_or("unknown");
+    let ollama_mode = match backend {
+        "ollama" => get_ollama_mode().ok().map(|mode| mode.label().to_string()),
+        _ => None,
+    };
+    (backend.to_string(), ollama_mode)
+}
+
+impl HardwareInfo {
+    fn probe() -> Self {
+        let mut system = System::new();
+        system.refresh_cpu_all();
+        system.refresh_memory();
+
+        let gpu = match gpu_inventory() {
+            Ok(gpu) => Some(gpu),
+            Err(e) => {
+                warn!("No GPU information available for node info: {}", e);
+                None
+            }
+        };
+
+        Self {
+            hostname: System::host_name(),
+            os: System::long_os_version(),
+            cpu: CpuInfo {
+                model: system
+                    .cpus()
+                    .first()
+                    .map(|cpu| cpu.brand().trim().to_string())
+                    .unwrap_or_default(),
+                logical_cores: system.cpus().len(),
+                physical_cores: system.physical_core_count(),
+            },
+            memory: MemoryInfo {
+                total_bytes: system.total_memory(),
+                swap_total_bytes: system.total_swap(),
+            },
+            gpu,
+        }
+    }
+}
+
+/// Formats NVML's CUDA driver version (e.g. `12020`) as `major.minor`.
+pub fn format_cuda_version(version: i32) -> String {
+    format!("{}.{}", version / 1000, (version % 1000) / 10)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{format_cuda_version, SUPPORTED_FEATURES};
+
+    #[test]
+    fn formats_cuda_driver_version() {
+        assert_eq!(format_cuda_version(12020), "12.2");
+        assert_eq!(format_cuda_version(11080), "11.8");
+    }
+
+    #[test]
+    fn advertises_protocol_features() {
+        for feature in [
+            "tls",
+            "challenge-auth",
+            "auth-result",
+            "node-info",
+            "heartbeat",
+            "job-lease",
+            "pause",
+            "status",
+            "model-commands",
+            "control-envelope-v1",
+            "events",
+            "self-update",
+            "signed-control",
+            "protocol-error",
+        ] {
+            assert!(SUPPORTED_FEATURES.contains(&feature), "{feature}");
+        }
+    }
+}
@@ -0,0 +1,52 @@
+use reqwest::Client;
+use serde::Serialize;
+
+use crate::logging::{latest_load, LoadSample};
+use crate::models::node_info::backend_kind;
+use crate::protocol::backend::{backend_version, discover_models};
+use crate::protocol::cancel::{running_jobs, JobStatus};
+use crate::protocol::state::{
+    active_connections, get_node_name, get_reboot, get_shutdown, is_paused, uptime,
+};
+
+/// What the node is doing right now, returned for HiveCore's `STATUS`.
+#[derive(Debug, Serialize)]
+pub struct NodeStatus {
+    pub node_name: String,
+    pub node_version: String,
+    pub backend: String,
+    pub backend_version: String,
+    pub ollama_mode: Option<String>,
+    /// `None` when the backend could not be asked for its models.
+    pub models: Option<Vec<String>>,
+    pub active_connections: usize,
+    pub jobs: Vec<JobStatus>,
+    pub paused: bool,
+    pub reboot: bool,
+    pub shutdown: bool,
+    pub uptime_secs: u64,
+    /// `None` until the load logger took its first sample.
+    pub load: Option<LoadSample>,
+}
+
+impl NodeStatus {
+    pub async fn collect(client: &Client) -> Self {
+        let (backend, ollama_mode) = backend_kind();
+
+        Self {
+            node_name: get_node_name(),
+            node_version: env!("CARGO_PKG_VERSION").to_string(),
+            backend,
+            backend_version: backend_version(client).await,
+            ollama_mode,
+            models: discover_models(client).await.ok(),
+            active_connections: active_connections(),
+            jobs: running_jobs(),
+            paused: is_paused(),
+            reboot: get_reboot(),
+            shutdown: get_shutdown(),
+            uptime_secs: uptime().as_secs(),
+            load: latest_load(),
+        }
+    }
+}
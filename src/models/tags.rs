@@ -1,7 +1,4 @@
-use anyhow::Result;
-use reqwest::blocking::Response;
 use serde::{Deserialize, Serialize};
-use std::convert::TryFrom;
 
 #[derive(Debug, Deserialize, Serialize)]
 pub struct ModelDetails {
@@ -28,27 +25,7 @@ pub struct Tags {
     pub models: Vec<Model>,
 }
 
-impl TryFrom<Response> for Tags {
-    type Error = anyhow::Error;
-
-    fn try_from(response: Response) -> Result<Self> {
-        let body = response.text()?;
-        let models_response: Tags = serde_json::from_str(&body)?;
-        Ok(models_response)
-    }
-}
-
 #[derive(Debug, Deserialize, Serialize)]
 pub struct Version {
     pub version: String,
 }
-
-impl TryFrom<Response> for Version {
-    type Error = anyhow::Error;
-
-    fn try_from(response: Response) -> Result<Self> {
-        let body = response.text()?;
-        let models_response: Version = serde_json::from_str(&body)?;
-        Ok(models_response)
-    }
-}
@@ -0,0 +1,237 @@
+use anyhow::Result;
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+use std::env;
+use std::fmt::{Display, Formatter};
+
+use crate::messages::proxy_message::ProxyMessage;
+
+type HmacSha256 = Hmac<Sha256>;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum AuthMode {
+    /// HiveCore sends a server nonce and the node proves it holds the key with
+    /// an HMAC, so the key never goes over the wire.
+    Challenge,
+    /// The raw key is sent in the `AUTH` line. Kept for HiveCore versions that
+    /// do not support the challenge handshake yet.
+    Legacy,
+}
+
+impl AuthMode {
+    fn parse(value: &str) -> Result<Self> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "" | "challenge" | "hmac" => Ok(Self::Challenge),
+            "legacy" => Ok(Self::Legacy),
+            other => Err(anyhow::anyhow!(
+                "Unsupported HIVE_AUTH_MODE `{other}`. Use `challenge` or `legacy`."
+            )),
+        }
+    }
+}
+
+pub fn get_auth_mode() -> Result<AuthMode> {
+    match env::var("HIVE_AUTH_MODE") {
+        Ok(value) => AuthMode::parse(&value),
+        Err(_) => Ok(AuthMode::Challenge),
+    }
+}
+
+/// Public identifier HiveCore uses to look up which worker key to verify the
+/// challenge response against: the first 16 hex chars of `SHA-256(key)`.
+pub fn key_id(key: &str) -> String {
+    let digest = Sha256::digest(key.as_bytes());
+    hex::encode(&digest[..8])
+}
+
+/// Answer to a HiveCore challenge: `hex(HMAC-SHA256(key, "<server_nonce>;<nonce>"))`.
+pub fn challenge_response(key: &str, server_nonce: &str, nonce: u64) -> String {
+    let mut mac =
+        HmacSha256::new_from_slice(key.as_bytes()).expect("HMAC accepts keys of any length");
+    mac.update(format!("{server_nonce};{nonce}").as_bytes());
+    hex::encode(mac.finalize().into_bytes())
+}
+
+/// Why HiveCore refused to authenticate this node.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum AuthError {
+    InvalidKey(String),
+    RevokedKey(String),
+    Banned(String),
+    VersionMismatch(String),
+    /// A rejection with a reason this node does not know about.
+    Rejected {
+        reason: String,
+        message: String,
+    },
+    /// HiveCore answered with something that is neither `AUTH-OK` nor `AUTH-FAIL`.
+    UnexpectedResponse(String),
+}
+
+impl AuthError {
+    fn from_failure(reason: &str, message: String) -> Self {
+        match reason.to_ascii_uppercase().as_str() {
+            "INVALID_KEY" => Self::InvalidKey(message),
+            "REVOKED_KEY" => Self::RevokedKey(message),
+            "BANNED" => Self::Banned(message),
+            "VERSION_MISMATCH" => Self::VersionMismatch(message),
+            _ => Self::Rejected {
+                reason: reason.to_string(),
+                message,
+            },
+        }
+    }
+
+    /// Permanent failures cannot be fixed by reconnecting and should stop the node.
+    pub fn is_permanent(&self) -> bool {
+        matches!(
+            self,
+            Self::InvalidKey(_) | Self::RevokedKey(_) | Self::Banned(_) | Self::VersionMismatch(_)
+        )
+    }
+
+    /// Process exit code used when a permanent failure stops the node.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Self::InvalidKey(_) => 10,
+            Self::RevokedKey(_) => 11,
+            Self::Banned(_) => 12,
+            Self::VersionMismatch(_) => 13,
+            Self::Rejected { .. } | Self::UnexpectedResponse(_) => 1,
+        }
+    }
+}
+
+impl Display for AuthError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::InvalidKey(message) => write!(f, "invalid worker key: {message}"),
+            Self::RevokedKey(message) => write!(f, "worker key was revoked: {message}"),
+            Self::Banned(message) => write!(f, "node is banned: {message}"),
+            Self::VersionMismatch(message) => {
+                write!(f, "HiveCore does not accept this node version: {message}")
+            }
+            Self::Rejected { reason, message } => {
+                write!(f, "authentication rejected ({reason}): {message}")
+            }
+            Self::UnexpectedResponse(response) => {
+                write!(f, "unexpected authentication response `{response}`")
+            }
+        }
+    }
+}
+
+impl std::error::Error for AuthError {}
+
+/// Fails with the matching [`AuthError`] if `response` is an `AUTH-FAIL` message.
+pub fn check_auth_failure(response: &ProxyMessage) -> Result<(), AuthError> {
+    if response.protocol == "HIVE" && response.method == "AUTH-FAIL" {
+        return Err(AuthError::from_failure(
+            &response.uri,
+            response.body_text().trim().to_string(),
+        ));
+    }
+    Ok(())
+}
+
+/// Reads the node name out of HiveCore's authentication result.
+///
+/// HiveCore answers `AUTH-OK <node_name> HIVE` or `AUTH-FAIL <reason> HIVE`
+/// with a human readable body. Cores that predate these replies send some other
+/// message whose URI is the node name; that is only accepted in legacy mode.
+pub fn parse_auth_result(response: &ProxyMessage, mode: AuthMode) -> Result<String, AuthError> {
+    check_auth_failure(response)?;
+    match (response.method.as_str(), mode) {
+        ("AUTH-OK", _) if !response.uri.is_empty() => Ok(response.uri.clone()),
+        (_, AuthMode::Legacy) if !response.uri.is_empty() => Ok(response.uri.clone()),
+        _ => Err(AuthError::UnexpectedResponse(format!(
+            "{} {} {}",
+            response.method, response.uri, response.protocol
+        ))),
+    }
+}
+
+/// Protocol features HiveCore offers in the `Features` header of `AUTH-OK`.
+pub fn core_features(response: &ProxyMessage) -> Vec<String> {
+    response
+        .headers
+        .get_all("Features")
+        .flat_map(|value| value.split(','))
+        .map(|feature| feature.trim().to_ascii_lowercase())
+        .filter(|feature| !feature.is_empty())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        challenge_response, core_features, key_id, parse_auth_result, AuthError, AuthMode,
+        ProxyMessage, Result,
+    };
+
+    fn hive_message(method: &str, uri: &str, body: &str) -> ProxyMessage {
+        ProxyMessage {
+            protocol: "HIVE".into(),
+            method: method.into(),
+            uri: uri.into(),
+            headers: Default::default(),
+            body: body.as_bytes().to_vec(),
+        }
+    }
+
+    #[test]
+    fn parses_auth_modes() -> Result<()> {
+        assert_eq!(AuthMode::parse("")?, AuthMode::Challenge);
+        assert_eq!(AuthMode::parse("legacy")?, AuthMode::Legacy);
+        assert!(AuthMode::parse("plain").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn challenge_response_matches_reference_hmac() {
+        // echo -n "server-nonce;42" | openssl dgst -sha256 -hmac "worker-secret"
+        assert_eq!(
+            challenge_response("worker-secret", "server-nonce", 42),
+            "34d1ca6dc501455f99b4761d8babdd07ec2d673e7262112037b65158b6e4b515"
+        );
+        assert_eq!(key_id("worker-secret").len(), 16);
+    }
+
+    #[test]
+    fn parses_auth_results() {
+        let ok = hive_message("AUTH-OK", "gpu-box-1", "");
+        assert_eq!(
+            parse_auth_result(&ok, AuthMode::Challenge).as_deref(),
+            Ok("gpu-box-1")
+        );
+
+        let revoked = hive_message("AUTH-FAIL", "REVOKED_KEY", "Key revoked by admin\n");
+        let error = parse_auth_result(&revoked, AuthMode::Challenge).unwrap_err();
+        assert_eq!(error, AuthError::RevokedKey("Key revoked by admin".into()));
+        assert!(error.is_permanent());
+
+        let busy = hive_message("AUTH-FAIL", "TOO_MANY_CONNECTIONS", "");
+        assert!(!parse_auth_result(&busy, AuthMode::Legacy)
+            .unwrap_err()
+            .is_permanent());
+    }
+
+    #[test]
+    fn accepts_legacy_auth_reply_only_in_legacy_mode() {
+        let legacy = hive_message("PONG", "gpu-box-1", "");
+        assert_eq!(
+            parse_auth_result(&legacy, AuthMode::Legacy).as_deref(),
+            Ok("gpu-box-1")
+        );
+        assert!(parse_auth_result(&legacy, AuthMode::Challenge).is_err());
+    }
+
+    #[test]
+    fn reads_core_features_from_auth_ok() {
+        let response =
+            ProxyMessage::parse(b"AUTH-OK node-1 HIVE\r\nFeatures: mux, Heartbeat\r\n\r\n")
+                .unwrap();
+        assert_eq!(core_features(&response), vec!["mux", "heartbeat"]);
+        assert!(core_features(&hive_message("AUTH-OK", "node-1", "")).is_empty());
+    }
+}
@@ -1,14 +1,14 @@
 use anyhow::{Context, Result};
 use log::info;
-use reqwest::blocking::{Client, Response};
 use reqwest::header::{HeaderName, HeaderValue, AUTHORIZATION};
+use reqwest::{Client, Response};
 use serde::Deserialize;
 use std::{env, time::Duration};
 
 use crate::messages::proxy_message::ProxyMessage;
 use crate::models::tags::{Tags, Version};
 
-use super::docker::{configure_ollama_runtime, configure_ollama_runtime_blocking};
+use super::docker::{configure_ollama_runtime, ensure_ollama_runtime};
 
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub enum InferenceBackend {
@@ -61,9 +61,11 @@ pub async fn configure_backend_runtime() -> Result<()> {
     }
 }
 
-pub fn configure_backend_runtime_blocking() -> Result<()> {
+/// Re-checks the backend before a reconnect, recreating the Docker-managed
+/// Ollama container if it went away.
+pub async fn ensure_backend_runtime() -> Result<()> {
     match get_backend()? {
-        InferenceBackend::Ollama => configure_ollama_runtime_blocking(),
+        InferenceBackend::Ollama => ensure_ollama_runtime().await,
         InferenceBackend::Vllm => {
             let backend_url = backend_base_url()?;
             env::set_var("BACKEND_URL", &backend_url);
@@ -73,22 +75,22 @@ pub fn configure_backend_runtime_blocking() -> Result<()> {
     }
 }
 
-pub fn discover_models(client: &Client) -> Result<Vec<String>> {
+pub async fn discover_models(client: &Client) -> Result<Vec<String>> {
     match get_backend()? {
-        InferenceBackend::Ollama => discover_ollama_models(client),
-        InferenceBackend::Vllm => discover_vllm_models(client),
+        InferenceBackend::Ollama => discover_ollama_models(client).await,
+        InferenceBackend::Vllm => discover_vllm_models(client).await,
     }
 }
 
-pub fn backend_version(client: &Client) -> String {
+pub async fn backend_version(client: &Client) -> String {
     match get_backend() {
-        Ok(InferenceBackend::Ollama) => ollama_version(client),
+        Ok(InferenceBackend::Ollama) => ollama_version(client).await,
         Ok(InferenceBackend::Vllm) => "vllm".to_string(),
         Err(_) => "Unknown".to_string(),
     }
 }
 
-pub fn make_backend_request(request: &ProxyMessage, client: &Client) -> Result<Response> {
+pub async fn make_backend_request(request: &ProxyMessage, client: &Client) -> Result<Response> {
     if request.protocol.eq("HIVE") {
         return Err(anyhow::anyhow!("Can't make HIVE requests to backend."));
     }
@@ -100,40 +102,39 @@ pub fn make_backend_request(request: &ProxyMessage, client: &Client) -> Result<R
 
     for (key, value) in request.headers.iter() {
         let key_lower = key.to_ascii_lowercase();
-        if key_lower != "host" && key_lower != "content-length" {
+        // `X-Hive-*` headers are meant for HiveNode, not the backend.
+        if key_lower != "host" && key_lower != "content-length" && !key_lower.starts_with("x-hive-")
+        {
             if let (Ok(header_name), Ok(header_value)) = (
                 HeaderName::from_bytes(key.as_bytes()),
-                HeaderValue::from_str(value),
+                HeaderValue::from_bytes(value.as_bytes()),
             ) {
                 request_builder = request_builder.header(header_name, header_value);
             }
         }
     }
 
-    if backend == InferenceBackend::Vllm
-        && !request
-            .headers
-            .keys()
-            .any(|key| key.eq_ignore_ascii_case("authorization"))
-    {
+    if backend == InferenceBackend::Vllm && !request.headers.contains("authorization") {
         if let Some(api_key) = backend_api_key() {
             request_builder = request_builder.header(AUTHORIZATION, format!("Bearer {api_key}"));
         }
     }
 
     if !request.body.is_empty() {
-        request_builder = request_builder.body(request.body.to_string());
+        request_builder = request_builder.body(request.body.clone());
     }
 
     Ok(request_builder
         .timeout(Duration::from_secs(60 * 30))
-        .send()?)
+        .send()
+        .await?)
 }
 
-fn discover_ollama_models(client: &Client) -> Result<Vec<String>> {
+async fn discover_ollama_models(client: &Client) -> Result<Vec<String>> {
     let req = ProxyMessage::new_http_get("/api/tags");
-    let resp = make_backend_request(&req, client)?;
-    Ok(Tags::try_from(resp)?
+    let resp = make_backend_request(&req, client).await?;
+    let tags: Tags = serde_json::from_str(&resp.text().await?)?;
+    Ok(tags
         .models
         .into_iter()
         .flat_map(|model| {
@@ -147,10 +148,10 @@ fn discover_ollama_models(client: &Client) -> Result<Vec<String>> {
         .collect())
 }
 
-fn discover_vllm_models(client: &Client) -> Result<Vec<String>> {
+async fn discover_vllm_models(client: &Client) -> Result<Vec<String>> {
     let req = ProxyMessage::new_http_get("/v1/models");
-    let resp = make_backend_request(&req, client)?;
-    let models_response: VllmModels = serde_json::from_str(&resp.text()?)?;
+    let resp = make_backend_request(&req, client).await?;
+    let models_response: VllmModels = serde_json::from_str(&resp.text().await?)?;
     Ok(models_response
         .data
         .into_iter()
@@ -158,13 +159,13 @@ fn discover_vllm_models(client: &Client) -> Result<Vec<String>> {
         .collect())
 }
 
-fn ollama_version(client: &Client) -> String {
+async fn ollama_version(client: &Client) -> String {
     let req = ProxyMessage::new_http_get("/api/version");
-    let resp = match make_backend_request(&req, client) {
-        Ok(resp) => resp,
+    let body = match make_backend_request(&req, client).await {
+        Ok(resp) => resp.text().await.unwrap_or_default(),
         Err(_) => return "Unknown".to_string(),
     };
-    match Version::try_from(resp) {
+    match serde_json::from_str::<Version>(&body) {
         Ok(v) => v.version,
         Err(_) => "Unknown".to_string(),
     }
@@ -0,0 +1,146 @@
+use lazy_static::lazy_static;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use tokio::sync::Notify;
+
+lazy_static! {
+    static ref RUNNING_JOBS: Mutex<HashMap<String, JobEntry>> = Mutex::new(HashMap::new());
+}
+
+/// Numbers jobs HiveCore did not name, so they can still be listed and
+/// cancelled.
+static NEXT_LOCAL_ID: AtomicU64 = AtomicU64::new(1);
+
+struct JobEntry {
+    cancel: Arc<Notify>,
+    model: Option<String>,
+    started: Instant,
+}
+
+/// Returned when HiveCore cancels a running job, either with `CANCEL` or by
+/// closing the connection the job streams to.
+#[derive(Debug)]
+pub struct JobCancelled;
+
+impl Display for JobCancelled {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Job cancelled by HiveCore")
+    }
+}
+
+impl std::error::Error for JobCancelled {}
+
+/// A proxied request in flight. It is listed in `STATUS` and can be cancelled
+/// by its ID from any connection; the registration is removed on drop.
+pub struct RunningJob {
+    job_id: String,
+    cancel: Arc<Notify>,
+}
+
+impl RunningJob {
+    /// Registers a job under HiveCore's job ID, or a local `local-<n>` ID
+    /// when the request has none.
+    pub fn register(job_id: Option<&str>, model: Option<String>) -> Self {
+        let job_id = match job_id {
+            Some(job_id) => job_id.to_string(),
+            None => format!("local-{}", NEXT_LOCAL_ID.fetch_add(1, Ordering::Relaxed)),
+        };
+        let cancel = Arc::new(Notify::new());
+        RUNNING_JOBS.lock().unwrap().insert(
+            job_id.clone(),
+            JobEntry {
+                cancel: cancel.clone(),
+                model,
+                started: Instant::now(),
+            },
+        );
+        Self { job_id, cancel }
+    }
+
+    pub fn id(&self) -> &str {
+        &self.job_id
+    }
+
+    /// Resolves once the job is cancelled.
+    pub async fn cancelled(&self) {
+        self.cancel.notified().await
+    }
+}
+
+impl Drop for RunningJob {
+    fn drop(&mut self) {
+        let mut jobs = RUNNING_JOBS.lock().unwrap();
+        // A newer job may have reused the ID; only remove our own entry.
+        if jobs
+            .get(&self.job_id)
+            .is_some_and(|job| Arc::ptr_eq(&job.cancel, &self.cancel))
+        {
+            jobs.remove(&self.job_id);
+        }
+    }
+}
+
+/// Cancels the running job with this ID. Returns whether it was running.
+pub fn cancel_job(job_id: &str) -> bool {
+    match RUNNING_JOBS.lock().unwrap().get(job_id) {
+        Some(job) => {
+            // `notify_one` keeps a permit, so a cancel that arrives before
+            // the job waits on it is not lost.
+            job.cancel.notify_one();
+            true
+        }
+        None => false,
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct JobStatus {
+    pub id: String,
+    pub model: Option<String>,
+    pub elapsed_secs: f64,
+}
+
+/// The jobs in flight, longest running first.
+pub fn running_jobs() -> Vec<JobStatus> {
+    let mut jobs: Vec<JobStatus> = RUNNING_JOBS
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(id, job)| JobStatus {
+            id: id.clone(),
+            model: job.model.clone(),
+            elapsed_secs: job.started.elapsed().as_secs_f64(),
+        })
+        .collect();
+    jobs.sort_by(|a, b| b.elapsed_secs.total_cmp(&a.elapsed_secs));
+    jobs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{cancel_job, running_jobs, RunningJob};
+
+    #[tokio::test]
+    async fn cancels_registered_jobs_by_id() {
+        let job = RunningJob::register(Some("job-cancel-test"), Some("llama3.2".into()));
+        assert!(running_jobs()
+            .iter()
+            .any(|status| status.id == "job-cancel-test"
+                && status.model.as_deref() == Some("llama3.2")));
+        assert!(cancel_job("job-cancel-test"));
+        job.cancelled().await;
+
+        drop(job);
+        assert!(!cancel_job("job-cancel-test"));
+    }
+
+    #[test]
+    fn names_jobs_without_an_id() {
+        let job = RunningJob::register(None, None);
+        assert!(job.id().starts_with("local-"));
+    }
+}
@@ -0,0 +1,161 @@
+use anyhow::{anyhow, Context, Result};
+use std::env;
+
+/// One HiveCore cluster this node serves: where to connect, which worker key to
+/// use, how many connections it gets and which models it may see.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ClusterConfig {
+    pub name: String,
+    pub core_url: String,
+    pub key: String,
+    pub concurrency: usize,
+    /// Models advertised to this cluster. `None` advertises everything.
+    pub models: Option<Vec<String>>,
+    /// Hex ed25519 key HiveCore signs control commands with. Without it,
+    /// commands are authenticated with an HMAC of `key`.
+    pub core_public_key: Option<String>,
+}
+
+impl ClusterConfig {
+    pub fn allows_model(&self, model: &str) -> bool {
+        match &self.models {
+            None => true,
+            Some(models) => models
+                .iter()
+                .any(|allowed| strip_latest(allowed) == strip_latest(model)),
+        }
+    }
+
+    pub fn filter_models(&self, models: Vec<String>) -> Vec<String> {
+        models
+            .into_iter()
+            .filter(|model| self.allows_model(model))
+            .collect()
+    }
+}
+
+/// Loads the clusters from the environment.
+///
+/// Without `HIVE_CLUSTERS` the node serves a single cluster configured by
+/// `HIVE_CORE_URL`, `HIVE_KEY`, `CONCURRENT_REQUESTS` and optionally
+/// `HIVE_MODELS` and `HIVE_CORE_PUBLIC_KEY`. With `HIVE_CLUSTERS=research,prod` every cluster reads the
+/// same settings prefixed with its upper-cased name, e.g.
+/// `HIVE_RESEARCH_CORE_URL`, `HIVE_RESEARCH_KEY`,
+/// `HIVE_RESEARCH_CONCURRENT_REQUESTS`, `HIVE_RESEARCH_MODELS` and
+/// `HIVE_RESEARCH_CORE_PUBLIC_KEY`.
+pub fn load_clusters() -> Result<Vec<ClusterConfig>> {
+    let names = match env::var("HIVE_CLUSTERS") {
+        Ok(names) if !names.trim().is_empty() => names,
+        _ => return Ok(vec![load_default_cluster()?]),
+    };
+
+    let clusters = names
+        .split(',')
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+        .map(load_named_cluster)
+        .collect::<Result<Vec<_>>>()?;
+
+    if clusters.is_empty() {
+        return Err(anyhow!("HIVE_CLUSTERS does not name any cluster"));
+    }
+    Ok(clusters)
+}
+
+/// Key used to name node-wide resources such as the Ollama container: the key
+/// of the first configured cluster.
+pub fn primary_key() -> Result<String> {
+    Ok(load_clusters()?.remove(0).key)
+}
+
+fn load_default_cluster() -> Result<ClusterConfig> {
+    Ok(ClusterConfig {
+        name: "default".to_string(),
+        core_url: env::var("HIVE_CORE_URL").context("HIVE_CORE_URL must be set")?,
+        key: env::var("HIVE_KEY").context("HIVE_KEY must be set")?,
+        concurrency: parse_concurrency(
+            "CONCURRENT_REQUESTS",
+            &env::var("CONCURRENT_REQUESTS").context("CONCURRENT_REQUESTS must be set")?,
+        )?,
+        models: env::var("HIVE_MODELS")
+            .ok()
+            .and_then(|raw| parse_models(&raw)),
+        core_public_key: env::var("HIVE_CORE_PUBLIC_KEY")
+            .ok()
+            .filter(|key| !key.trim().is_empty()),
+    })
+}
+
+fn load_named_cluster(name: &str) -> Result<ClusterConfig> {
+    let prefix = format!("HIVE_{}_", name.to_ascii_uppercase().replace('-', "_"));
+    let var = |suffix: &str| -> Result<String> {
+        let key = format!("{prefix}{suffix}");
+        env::var(&key).with_context(|| format!("{key} must be set for cluster `{name}`"))
+    };
+
+    Ok(ClusterConfig {
+        name: name.to_string(),
+        core_url: var("CORE_URL")?,
+        key: var("KEY")?,
+        concurrency: parse_concurrency(
+            &format!("{prefix}CONCURRENT_REQUESTS"),
+            &var("CONCURRENT_REQUESTS")?,
+        )?,
+        models: var("MODELS").ok().and_then(|raw| parse_models(&raw)),
+        core_public_key: var("CORE_PUBLIC_KEY")
+            .ok()
+            .filter(|key| !key.trim().is_empty()),
+    })
+}
+
+fn parse_concurrency(name: &str, raw: &str) -> Result<usize> {
+    raw.trim()
+        .parse::<usize>()
+        .with_context(|| format!("{name} must be a whole number"))
+}
+
+fn parse_models(raw: &str) -> Option<Vec<String>> {
+    let models: Vec<String> = raw
+        .split([',', ';'])
+        .map(str::trim)
+        .filter(|model| !model.is_empty())
+        .map(String::from)
+        .collect();
+    (!models.is_empty()).then_some(models)
+}
+
+fn strip_latest(model: &str) -> &str {
+    model.strip_suffix(":latest").unwrap_or(model)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_models, ClusterConfig};
+
+    #[test]
+    fn allowlist_matches_with_and_without_latest_tag() {
+        let cluster = ClusterConfig {
+            name: "research".into(),
+            core_url: "core:7777".into(),
+            key: "key".into(),
+            concurrency: 1,
+            models: parse_models("llama3.2, qwen3:8b"),
+            core_public_key: None,
+        };
+
+        assert_eq!(
+            cluster.filter_models(vec![
+                "llama3.2".into(),
+                "llama3.2:latest".into(),
+                "qwen3:8b".into(),
+                "qwen3:32b".into(),
+            ]),
+            vec!["llama3.2", "llama3.2:latest", "qwen3:8b"]
+        );
+    }
+
+    #[test]
+    fn empty_allowlist_allows_everything() {
+        assert_eq!(parse_models(" , "), None);
+    }
+}
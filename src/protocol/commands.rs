@@ -0,0 +1,507 @@
+use anyhow::{anyhow, Context, Result};
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use log::{error, info, warn};
+use reqwest::Client;
+use serde::Deserialize;
+use serde_json::{Map, Value};
+
+use crate::messages::proxy_message::ProxyMessage;
+use crate::models::node_status::NodeStatus;
+use crate::protocol::state::notify_refresh;
+
+use super::backend::{get_backend, InferenceBackend};
+use super::cancel::cancel_job;
+use super::cluster::ClusterConfig;
+use super::docker::{is_docker_managed, upgrade_ollama_docker};
+use super::events::report_completion;
+use super::model_admin::ModelCommand;
+use super::self_update::{start_node_update, UpdateArgs, UpdateConfig};
+use super::state::{set_paused, set_reboot, set_shutdown};
+
+/// The only version of the JSON control envelope this node understands.
+pub const CONTROL_VERSION: u64 = 1;
+
+/// Carries the request ID of a bare command, and echoes it on every reply.
+pub const REQUEST_ID_HEADER: &str = "X-Hive-Request-Id";
+
+/// The JSON body of a `CONTROL / HIVE` message.
+#[derive(Debug, Deserialize)]
+struct Envelope {
+    #[serde(default = "default_version")]
+    version: u64,
+    command: String,
+    #[serde(default)]
+    args: Value,
+    request_id: Option<String>,
+}
+
+fn default_version() -> u64 {
+    CONTROL_VERSION
+}
+
+/// A control command from HiveCore, whichever form it arrived in.
+#[derive(Debug, PartialEq)]
+pub struct ControlRequest {
+    pub command: String,
+    pub args: Value,
+    pub request_id: Option<String>,
+}
+
+impl ControlRequest {
+    /// Reads the command carried by `message`: a versioned JSON envelope sent
+    /// as `CONTROL / HIVE`, or the bare `<COMMAND> <arg> HIVE` form with JSON
+    /// arguments in the body. Returns `None` for messages that are not
+    /// commands; an envelope may name a command this node does not know.
+    pub fn from_message(message: &ProxyMessage) -> Result<Option<Self>> {
+        match (
+            message.protocol.as_str(),
+            message.method.as_str(),
+            message.uri.as_str(),
+        ) {
+            ("HIVE", "CONTROL", _) => Self::from_envelope(&message.body).map(Some),
+            ("HIVE", command, uri) => Ok(find_command(command).map(|spec| Self {
+                command: command.to_string(),
+                args: bare_args(spec, uri, message),
+                request_id: message.header(REQUEST_ID_HEADER).map(String::from),
+            })),
+            _ => Ok(None),
+        }
+    }
+
+    fn from_envelope(body: &[u8]) -> Result<Self> {
+        let envelope: Envelope = serde_json::from_slice(body).context("Invalid control message")?;
+        if envelope.version != CONTROL_VERSION {
+            return Err(anyhow!(
+                "Unsupported control message version {}",
+                envelope.version
+            ));
+        }
+        Ok(Self {
+            command: envelope.command,
+            args: envelope.args,
+            request_id: envelope.request_id,
+        })
+    }
+
+    /// The string argument `name`, if given.
+    pub fn arg_str(&self, name: &str) -> Option<&str> {
+        self.args.get(name)?.as_str()
+    }
+}
+
+/// Path of the old HTTP command form, which is never proxied nor run.
+pub const WORKER_COMMAND_PATH: &str = "/worker/command";
+
+/// Whether `message` is meant for the node rather than the backend: a HIVE
+/// message, or an HTTP request to `/worker/command`, which is refused.
+pub fn is_command_request(message: &ProxyMessage) -> bool {
+    message.protocol == "HIVE" || message.uri.split('?').next() == Some(WORKER_COMMAND_PATH)
+}
+
+/// Arguments of the bare form: the JSON body, plus the URI under the name
+/// the command gives it.
+fn bare_args(spec: &CommandSpec, uri: &str, message: &ProxyMessage) -> Value {
+    let mut args = message.json().unwrap_or(Value::Null);
+    if let Some(name) = spec.uri_arg {
+        if !matches!(args, Value::Object(_)) {
+            args = Value::Object(Map::new());
+        }
+        args[name] = Value::String(uri.to_string());
+    }
+    args
+}
+
+/// What a command answers on the worker stream. Every reply echoes the
+/// request ID of the command.
+#[derive(Debug)]
+pub enum CommandReply {
+    Text {
+        status: &'static str,
+        body: String,
+    },
+    Json {
+        status: &'static str,
+        body: String,
+    },
+    /// Proxied to the backend; its response, including any streamed
+    /// progress, is the reply.
+    Backend(ProxyMessage),
+}
+
+impl CommandReply {
+    fn text(status: &'static str, body: &str) -> Self {
+        Self::Text {
+            status,
+            body: body.to_string(),
+        }
+    }
+}
+
+pub type CommandHandler = for<'a> fn(
+    &'a ControlRequest,
+    &'a ClusterConfig,
+    &'a Client,
+) -> BoxFuture<'a, Result<CommandReply>>;
+
+pub struct CommandSpec {
+    pub name: &'static str,
+    /// The argument the URI of the bare form fills in, e.g. the job ID of
+    /// `CANCEL <job-id> HIVE`.
+    pub uri_arg: Option<&'static str>,
+    pub run: CommandHandler,
+}
+
+/// Every command HiveCore can send. Adding a command only takes an entry
+/// here.
+pub static COMMANDS: &[CommandSpec] = &[
+    CommandSpec {
+        name: "REBOOT",
+        uri_arg: None,
+        run: reboot,
+    },
+    CommandSpec {
+        name: "SHUTDOWN",
+        uri_arg: None,
+        run: shutdown,
+    },
+    CommandSpec {
+        name: "PAUSE",
+        uri_arg: None,
+        run: pause,
+    },
+    CommandSpec {
+        name: "RESUME",
+        uri_arg: None,
+        run: resume,
+    },
+    CommandSpec {
+        name: "STATUS",
+        uri_arg: None,
+        run: status,
+    },
+    CommandSpec {
+        name: "CANCEL",
+        uri_arg: Some("job_id"),
+        run: cancel,
+    },
+    CommandSpec {
+        name: "UPDATE",
+        uri_arg: None,
+        run: update_ollama,
+    },
+    CommandSpec {
+        name: "UPDATE_OLLAMA",
+        uri_arg: None,
+        run: update_ollama,
+    },
+    CommandSpec {
+        name: "UPDATE_NODE",
+        uri_arg: None,
+        run: update_node,
+    },
+    CommandSpec {
+        name: "PULL_MODEL",
+        uri_arg: None,
+        run: model_command,
+    },
+    CommandSpec {
+        name: "DELETE_MODEL",
+        uri_arg: None,
+        run: model_command,
+    },
+    CommandSpec {
+        name: "COPY_MODEL",
+        uri_arg: None,
+        run: model_command,
+    },
+];
+
+pub fn find_command(name: &str) -> Option<&'static CommandSpec> {
+    COMMANDS.iter().find(|spec| spec.name == name)
+}
+
+fn reboot<'a>(
+    _: &'a ControlRequest,
+    _: &'a ClusterConfig,
+    _: &'a Client,
+) -> BoxFuture<'a, Result<CommandReply>> {
+    async {
+        set_reboot(true);
+        Ok(CommandReply::text("200 OK", "HiveNode will reconnect.\n"))
+    }
+    .boxed()
+}
+
+fn shutdown<'a>(
+    _: &'a ControlRequest,
+    _: &'a ClusterConfig,
+    _: &'a Client,
+) -> BoxFuture<'a, Result<CommandReply>> {
+    async {
+        set_shutdown(true);
+        Ok(CommandReply::text("200 OK", "HiveNode is draining.\n"))
+    }
+    .boxed()
+}
+
+fn pause<'a>(
+    _: &'a ControlRequest,
+    _: &'a ClusterConfig,
+    _: &'a Client,
+) -> BoxFuture<'a, Result<CommandReply>> {
+    async {
+        set_paused(true);
+        Ok(CommandReply::text("200 OK", "HiveNode paused.\n"))
+    }
+    .boxed()
+}
+
+fn resume<'a>(
+    _: &'a ControlRequest,
+    _: &'a ClusterConfig,
+    _: &'a Client,
+) -> BoxFuture<'a, Result<CommandReply>> {
+    async {
+        set_paused(false);
+        Ok(CommandReply::text("200 OK", "HiveNode resumed.\n"))
+    }
+    .boxed()
+}
+
+fn status<'a>(
+    _: &'a ControlRequest,
+    _: &'a ClusterConfig,
+    client: &'a Client,
+) -> BoxFuture<'a, Result<CommandReply>> {
+    async move {
+        Ok(CommandReply::Json {
+            status: "200 OK",
+            body: serde_json::to_string(&NodeStatus::collect(client).await)?,
+        })
+    }
+    .boxed()
+}
+
+fn cancel<'a>(
+    request: &'a ControlRequest,
+    _: &'a ClusterConfig,
+    _: &'a Client,
+) -> BoxFuture<'a, Result<CommandReply>> {
+    let reply = match request.arg_str("job_id") {
+        Some(job_id) if cancel_job(job_id) => CommandReply::text("200 OK", "Job cancelled.\n"),
+        Some(_) => CommandReply::text("404 Not Found", "No such running job.\n"),
+        None => CommandReply::text("400 Bad Request", "CANCEL needs a job_id.\n"),
+    };
+    async { Ok(reply) }.boxed()
+}
+
+/// Upgrades the Docker-managed Ollama in the background. Its outcome is
+/// reported to the cluster that asked as a completion event.
+fn update_ollama<'a>(
+    request: &'a ControlRequest,
+    cluster: &'a ClusterConfig,
+    client: &'a Client,
+) -> BoxFuture<'a, Result<CommandReply>> {
+    async move {
+        if get_backend()? != InferenceBackend::Ollama {
+            warn!("Ignoring UPDATE_OLLAMA because HiveNode is using a vLLM backend.");
+            return Ok(CommandReply::text(
+                "409 Conflict",
+                "UPDATE_OLLAMA is only available when INFERENCE_BACKEND=ollama.\n",
+            ));
+        }
+
+        if !is_docker_managed() {
+            warn!("Ignoring UPDATE_OLLAMA because HiveNode is using an external Ollama instance.");
+            return Ok(CommandReply::text(
+                "409 Conflict",
+                "UPDATE_OLLAMA is only available when OLLAMA_MODE=docker.\n",
+            ));
+        }
+
+        // The outcome is reported on the next connection, so the node
+        // reconnects either way.
+        let upgrade = report_completion(
+            request.command.clone(),
+            request.request_id.clone(),
+            cluster.name.clone(),
+            client.clone(),
+            upgrade_ollama_docker(),
+        );
+        tokio::spawn(async {
+            warn!("Attempting to upgrade Ollama Docker container...");
+            match upgrade.await {
+                Ok(_) => {
+                    info!("Ollama Docker upgrade completed successfully.");
+                    notify_refresh();
+                }
+                Err(e) => {
+                    error!("Failed to upgrade Ollama Docker: {}", e);
+                }
+            }
+            set_reboot(true);
+            info!("Done updating Ollama Docker container...");
+        });
+
+        Ok(CommandReply::text(
+            "202 Accepted",
+            "Ollama Docker update started. HiveNode will reconnect when ready.\n",
+        ))
+    }
+    .boxed()
+}
+
+/// Replaces the HiveNode binary with a signed release and restarts into it.
+fn update_node<'a>(
+    request: &'a ControlRequest,
+    cluster: &'a ClusterConfig,
+    _: &'a Client,
+) -> BoxFuture<'a, Result<CommandReply>> {
+    async move {
+        let Some(config) = UpdateConfig::from_env()? else {
+            warn!("Ignoring UPDATE_NODE because self-update is not configured.");
+            return Ok(CommandReply::text(
+                "409 Conflict",
+                "UPDATE_NODE needs HIVE_UPDATE_URL and HIVE_UPDATE_PUBLIC_KEY.\n",
+            ));
+        };
+        let args: UpdateArgs = match Deserialize::deserialize(&request.args) {
+            Ok(args) => args,
+            Err(e) => {
+                warn!("Rejecting UPDATE_NODE: {}", e);
+                return Ok(CommandReply::Text {
+                    status: "400 Bad Request",
+                    body: format!("UPDATE_NODE has missing or invalid arguments: {e}\n"),
+                });
+            }
+        };
+        if let Err(e) = start_node_update(
+            config,
+            args,
+            request.request_id.clone(),
+            cluster.name.clone(),
+        ) {
+            warn!("Rejecting UPDATE_NODE: {:#}", e);
+            return Ok(CommandReply::Text {
+                status: "409 Conflict",
+                body: format!("{e:#}\n"),
+            });
+        }
+        Ok(CommandReply::text(
+            "202 Accepted",
+            "HiveNode update started. HiveNode will restart when ready.\n",
+        ))
+    }
+    .boxed()
+}
+
+/// Runs a model management command against Ollama and relays its response,
+/// including pull progress.
+fn model_command<'a>(
+    request: &'a ControlRequest,
+    _: &'a ClusterConfig,
+    _: &'a Client,
+) -> BoxFuture<'a, Result<CommandReply>> {
+    async move {
+        let command = request.command.as_str();
+        if get_backend()? != InferenceBackend::Ollama {
+            warn!("Ignoring {command} because HiveNode is using a vLLM backend.");
+            return Ok(CommandReply::text(
+                "409 Conflict",
+                "Model commands are only available when INFERENCE_BACKEND=ollama.\n",
+            ));
+        }
+
+        match ModelCommand::parse(command, &request.args)
+            .and_then(|command| command.backend_request())
+        {
+            Ok(backend_request) => Ok(CommandReply::Backend(backend_request)),
+            Err(e) => {
+                warn!("Rejecting {}: {:#}", command, e);
+                Ok(CommandReply::Text {
+                    status: "400 Bad Request",
+                    body: format!("{e:#}\n"),
+                })
+            }
+        }
+    }
+    .boxed()
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::{find_command, ControlRequest};
+    use crate::messages::proxy_message::ProxyMessage;
+
+    fn parse(raw: &str) -> Option<ControlRequest> {
+        ControlRequest::from_message(&ProxyMessage::parse(raw.as_bytes()).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn parses_control_envelopes() {
+        let request = parse(
+            "CONTROL / HIVE\r\n\r\n{\"version\":1,\"command\":\"PULL_MODEL\",\"args\":{\"model\":\"llama3.2\"},\"request_id\":\"r-7\"}",
+        )
+        .unwrap();
+        assert_eq!(
+            request,
+            ControlRequest {
+                command: "PULL_MODEL".into(),
+                args: json!({"model": "llama3.2"}),
+                request_id: Some("r-7".into()),
+            }
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_envelope_versions() {
+        let message =
+            ProxyMessage::parse(b"CONTROL / HIVE\r\n\r\n{\"version\":2,\"command\":\"STATUS\"}")
+                .unwrap();
+        assert!(ControlRequest::from_message(&message).is_err());
+    }
+
+    #[test]
+    fn parses_bare_commands_with_request_ids() {
+        let request = parse("CANCEL job-4 HIVE\r\nX-Hive-Request-Id: r-1\r\n\r\n").unwrap();
+        assert_eq!(request.arg_str("job_id"), Some("job-4"));
+        assert_eq!(request.request_id.as_deref(), Some("r-1"));
+
+        let pause = parse("PAUSE / HIVE\r\n\r\n").unwrap();
+        let resume = parse("RESUME / HIVE\r\n\r\n").unwrap();
+        assert_eq!(
+            (pause.command.as_str(), resume.command.as_str()),
+            ("PAUSE", "RESUME")
+        );
+        assert!(parse("PONG 3 HIVE\r\n\r\n").is_none());
+    }
+
+    #[test]
+    fn ignores_worker_command_http_body() {
+        assert!(parse("POST /worker/command HTTP/1.1\r\n\r\n UPDATE \n").is_none());
+    }
+
+    #[test]
+    fn registry_knows_every_command() {
+        for name in [
+            "REBOOT",
+            "SHUTDOWN",
+            "UPDATE",
+            "UPDATE_OLLAMA",
+            "UPDATE_NODE",
+            "PAUSE",
+            "RESUME",
+            "STATUS",
+            "CANCEL",
+            "PULL_MODEL",
+            "DELETE_MODEL",
+            "COPY_MODEL",
+        ] {
+            assert!(find_command(name).is_some(), "{name}");
+        }
+        assert!(find_command("NODE-INFO").is_none());
+    }
+}
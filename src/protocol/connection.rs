@@ -1,114 +1,204 @@
-use anyhow::{anyhow, Result};
-use chrono::{DateTime, Utc};
-use reqwest::blocking::Client;
-use std::{env, net::TcpStream};
+use anyhow::{anyhow, Context, Result};
+use log::{info, warn};
+use reqwest::Client;
+use std::sync::Arc;
+use tokio::io::AsyncWriteExt;
 
 use super::{
+    cancel::JobCancelled,
+    cluster::ClusterConfig,
     docker::DOCKER_UPGRADE_LOCK,
-    network_util::{handle_control_request, read_next_message, stream_response_to_proxy},
+    drain::announce_drain,
+    endpoints::{connect_preferred, parse_core_endpoints, EndpointConfig, Failback},
+    events::send_pending_events,
+    frame::{max_frame_bytes, FrameError, FrameReader},
+    heartbeat::{configure_socket, Heartbeat, HeartbeatConfig},
+    mux::{run_mux_session, ClusterSessions, SessionMode},
+    network_util::{
+        announce_pause, read_next_message, serve_watching_core, write_http_response, ServeOptions,
+    },
+    self_update::confirm_update,
     state::{
-        get_last_refresh, get_reboot, get_shutdown, init_local_time, notify_refresh,
-        refresh_poll_models,
+        get_reboot, get_shutdown, is_paused, notify_refresh, paused_becomes, session_end_requested,
+        ActiveConnection, PollState,
     },
+    streaming::StreamConfig,
+    transport::CoreStream,
 };
+use crate::messages::proxy_message::ParseError;
 use crate::protocol::network_util::{authenticate, poll};
 
-pub fn run_protocol(nonce: u64) -> Result<()> {
-    let proxy_server_url =
-        normalize_core_tcp_addr(&env::var("HIVE_CORE_URL").expect("HIVE_CORE_URL"))?;
-    let mut stream = TcpStream::connect(proxy_server_url.clone())?;
+/// Runs one HiveCore session until it ends. `on_connected` is called once the
+/// node has authenticated, so the caller can reset its reconnect backoff.
+///
+/// Slot 0 of a cluster offers multiplexing and publishes the negotiated mode
+/// to `sessions`; the other slots only run while jobs go one per connection.
+pub async fn run_protocol(
+    cluster: &Arc<ClusterConfig>,
+    sessions: &ClusterSessions,
+    slot: usize,
+    nonce: u64,
+    stream_config: &StreamConfig,
+    on_connected: impl FnOnce(),
+) -> Result<()> {
+    let endpoints = parse_core_endpoints(&cluster.core_url)?;
+    let endpoint_config = EndpointConfig::from_env()?;
+    let heartbeat_config = HeartbeatConfig::from_env()?;
+    let (active_endpoint, mut stream) = connect_preferred(&endpoints, &endpoint_config).await?;
+    let mut failback = Failback::new(endpoints, active_endpoint, endpoint_config);
+    configure_socket(&mut stream, &heartbeat_config)?;
     let client = Client::new();
-    let mut local_refresh_time: DateTime<Utc> = init_local_time();
-    let mut opzimized_poll = false;
-    let mut models = "/".to_string();
+    let mut reader = FrameReader::new(max_frame_bytes()?);
+    let mut polls = PollState::new();
+    let offer_mux = sessions.offers_mux(slot);
 
-    {
-        let _read_guard = DOCKER_UPGRADE_LOCK.read().unwrap();
+    let (mut heartbeat, features) = {
+        let _read_guard = DOCKER_UPGRADE_LOCK.read().await;
 
-        if let Err(e) = refresh_poll_models(&client, &mut local_refresh_time, &mut models) {
+        if let Err(e) = polls.refresh(&client, cluster).await {
             return Err(anyhow!(format!("Error refreshing available models: {}", e)));
         }
 
-        if let Err(e) = authenticate(&mut stream, nonce, &client) {
-            return Err(anyhow!(format!("Error authenticating: {}", e)));
-        }
+        let mut heartbeat = Heartbeat::new(heartbeat_config);
+
+        // Keep the error intact so the reconnect loop can tell permanent
+        // authentication failures apart from network errors.
+        let features = authenticate(
+            &mut stream,
+            &mut reader,
+            &mut heartbeat,
+            cluster,
+            nonce,
+            &client,
+            offer_mux,
+        )
+        .await
+        .context("Error authenticating")?;
+        (heartbeat, features)
+    };
+    let multiplexed = features.multiplexed;
+    let options = ServeOptions {
+        stream: stream_config.clone(),
+        leases: features.leases,
+    };
+    heartbeat.start();
+    on_connected();
+    let _active = ActiveConnection::register();
+    confirm_update(&client).await;
+    send_pending_events(&mut stream, &cluster.name, multiplexed)
+        .await
+        .context("Error reporting command events")?;
+
+    if offer_mux {
+        sessions.publish(if multiplexed {
+            SessionMode::Multiplexed
+        } else {
+            SessionMode::PerConnection
+        });
+    }
+    if multiplexed {
+        return run_mux_session(
+            stream, reader, heartbeat, failback, polls, cluster, &client, options,
+        )
+        .await;
     }
 
     loop {
-        let global_refresh_time = get_last_refresh();
-
-        if global_refresh_time > local_refresh_time {
-            {
-                let _read_guard = DOCKER_UPGRADE_LOCK.read().unwrap();
+        if polls.is_stale() {
+            let _read_guard = DOCKER_UPGRADE_LOCK.read().await;
 
-                if let Err(e) = refresh_poll_models(&client, &mut local_refresh_time, &mut models) {
-                    return Err(anyhow!(format!("Error refreshing models: {}", e)));
-                };
-            }
-            opzimized_poll = false;
+            if let Err(e) = polls.refresh(&client, cluster).await {
+                return Err(anyhow!(format!("Error refreshing models: {}", e)));
+            };
         }
 
-        if let Err(e) = poll(&mut stream, &models, &opzimized_poll) {
-            return Err(anyhow!(format!("Error polling HiveCore: {}", e)));
-        };
-
-        opzimized_poll = true;
+        // A paused node keeps reading, so heartbeats, commands and work that
+        // was sent before the pause are still handled.
+        let polling = !is_paused();
+        if polling {
+            if let Err(e) = poll(&mut stream, &mut polls).await {
+                return Err(anyhow!(format!("Error polling HiveCore: {}", e)));
+            };
+        }
 
-        let should_refresh_result: Result<bool> = {
-            let request = read_next_message(&mut stream)?;
-            if request.protocol == "HIVE" {
-                handle_control_request(&request, &mut stream)
-            } else {
-                let _read_guard = DOCKER_UPGRADE_LOCK.read().unwrap();
-                stream_response_to_proxy(request, &mut stream, &client)
+        // Waiting for work races the failback probe, pause changes and
+        // reboot/shutdown requests. The losing read is only dropped when the
+        // session ends, and the frame reader keeps partial frames anyway.
+        let request = tokio::select! {
+            request = read_next_message(&mut stream, &mut reader, &mut heartbeat) => match request {
+                Ok(request) => request,
+                Err(e) => {
+                    refuse_unreadable(&mut stream, e).await?;
+                    continue;
+                }
+            },
+            _ = paused_becomes(polling) => {
+                if polling {
+                    announce_pause(&mut stream).await?;
+                }
+                continue;
             }
+            preferred = failback.preferred_available() => {
+                info!(
+                    "Leaving standby HiveCore to reconnect to {}",
+                    preferred.addr
+                );
+                return Ok(());
+            }
+            _ = session_end_requested() => return end_session(&mut stream).await,
         };
 
-        if let Ok(should_refresh) = should_refresh_result {
-            if should_refresh {
-                notify_refresh()
-            }
+        // A failure halfway through a response leaves the stream in an unknown
+        // state, so the session ends and HiveCore sees the job was not done.
+        // A cancelled job ends it the same way, without counting as a failure.
+        match serve_watching_core(
+            request,
+            cluster,
+            &mut stream,
+            &mut reader,
+            &client,
+            &options,
+        )
+        .await
+        {
+            Ok(true) => notify_refresh(),
+            Ok(false) => {}
+            Err(e) if e.is::<JobCancelled>() => return Ok(()),
+            Err(e) => return Err(e.context("Error serving request")),
         }
 
         if get_reboot() || get_shutdown() {
-            return Ok(());
+            return end_session(&mut stream).await;
         }
     }
 }
 
-fn normalize_core_tcp_addr(raw_url: &str) -> Result<String> {
-    let trimmed = raw_url.trim();
-    let without_scheme = trimmed
-        .strip_prefix("http://")
-        .or_else(|| trimmed.strip_prefix("https://"))
-        .unwrap_or(trimmed);
-    let addr = without_scheme
-        .split('/')
-        .next()
-        .unwrap_or("")
-        .trim()
-        .to_string();
-
-    if addr.is_empty() {
-        return Err(anyhow!("HIVE_CORE_URL must include host:port"));
-    }
-
-    Ok(addr)
+/// Answers a request that could not be read, so the session can go on. When
+/// the stream can no longer be read, HiveCore is told why and the session
+/// ends with the error.
+async fn refuse_unreadable(stream: &mut CoreStream, e: anyhow::Error) -> Result<()> {
+    let status = match (
+        e.downcast_ref::<ParseError>(),
+        e.downcast_ref::<FrameError>(),
+    ) {
+        (Some(_), _) => "400 Bad Request",
+        (_, Some(FrameError::TooLarge { .. })) => "413 Payload Too Large",
+        (_, Some(refused)) => {
+            stream.write_all(refused.message().as_bytes()).await?;
+            stream.flush().await?;
+            return Err(e);
+        }
+        (None, None) => return Err(e),
+    };
+    warn!("Refusing request: {}", e);
+    write_http_response(stream, status, &format!("{e}.\n")).await
 }
 
-#[cfg(test)]
-mod tests {
-    use super::normalize_core_tcp_addr;
-
-    #[test]
-    fn normalizes_core_tcp_addr() {
-        assert_eq!(
-            normalize_core_tcp_addr("http://hivecore.example.com:7777").unwrap(),
-            "hivecore.example.com:7777"
-        );
-        assert_eq!(
-            normalize_core_tcp_addr("127.0.0.1:7777").unwrap(),
-            "127.0.0.1:7777"
-        );
+/// Ends a session for a reboot or shutdown. When shutting down, HiveCore is
+/// told first so it withdraws the outstanding poll.
+async fn end_session(stream: &mut CoreStream) -> Result<()> {
+    if get_shutdown() {
+        announce_drain(stream).await?;
     }
+    Ok(())
 }
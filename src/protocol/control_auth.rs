@@ -0,0 +1,392 @@
+use anyhow::{anyhow, Result};
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use influxdb2::models::DataPoint;
+use log::warn;
+use once_cell::sync::Lazy;
+use ring::signature::{UnparsedPublicKey, ED25519};
+use sha2::Sha256;
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::env;
+use std::fmt::{Display, Formatter};
+use std::sync::Mutex;
+
+use crate::logging::log_influx;
+use crate::messages::proxy_message::ProxyMessage;
+
+use super::cluster::ClusterConfig;
+use super::env_util::env_u64;
+use super::state::cluster_node_name;
+
+type HmacSha256 = Hmac<Sha256>;
+
+pub const TIMESTAMP_HEADER: &str = "X-Hive-Timestamp";
+pub const SIGNATURE_HEADER: &str = "X-Hive-Signature";
+/// A value HiveCore never reuses, so each signed command runs once.
+pub const NONCE_HEADER: &str = "X-Hive-Nonce";
+
+static SEEN_NONCES: Lazy<Mutex<SeenNonces>> = Lazy::new(Default::default);
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ControlSigning {
+    /// Every control command must be signed by HiveCore.
+    Required,
+    /// HIVE control frames are trusted as before. Kept for HiveCore versions
+    /// that do not sign commands yet; `/worker/command` is still refused.
+    Off,
+}
+
+impl ControlSigning {
+    fn parse(value: &str) -> Result<Self> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "" | "required" | "on" => Ok(Self::Required),
+            "off" => Ok(Self::Off),
+            other => Err(anyhow!(
+                "Unsupported HIVE_CONTROL_SIGNING `{other}`. Use `required` or `off`."
+            )),
+        }
+    }
+}
+
+/// How control commands are checked: `HIVE_CONTROL_SIGNING` and
+/// `HIVE_CONTROL_MAX_SKEW_SECS`. Validated at startup, so verifying a command
+/// does not fail on the configuration later.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct SigningConfig {
+    pub signing: ControlSigning,
+    /// Seconds a command's timestamp may be away from the node's clock.
+    pub max_skew: u64,
+}
+
+pub fn get_control_signing() -> Result<SigningConfig> {
+    let signing = match env::var("HIVE_CONTROL_SIGNING") {
+        Ok(value) => ControlSigning::parse(&value)?,
+        Err(_) => ControlSigning::Required,
+    };
+    Ok(SigningConfig {
+        signing,
+        max_skew: env_u64("HIVE_CONTROL_MAX_SKEW_SECS", 300)?,
+    })
+}
+
+/// Why a control command was not executed.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum ControlRejected {
+    Unsigned,
+    BadSignature,
+    /// The timestamp is this many seconds away from the node's clock.
+    Stale(u64),
+    /// The nonce was already used by a verified command.
+    Replayed,
+    /// The command came as an HTTP request rather than a HIVE frame.
+    NotAFrame,
+}
+
+impl ControlRejected {
+    pub fn reason(&self) -> &'static str {
+        match self {
+            Self::Unsigned => "unsigned",
+            Self::BadSignature => "bad_signature",
+            Self::Stale(_) => "stale",
+            Self::Replayed => "replayed",
+            Self::NotAFrame => "http_command",
+        }
+    }
+}
+
+impl Display for ControlRejected {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Unsigned => f.write_str("Control command is not signed"),
+            Self::BadSignature => f.write_str("Control command signature does not verify"),
+            Self::Stale(skew) => write!(f, "Control command timestamp is {skew}s off"),
+            Self::Replayed => f.write_str("Control command was already received"),
+            Self::NotAFrame => f.write_str("Control commands are only accepted as HIVE frames"),
+        }
+    }
+}
+
+impl std::error::Error for ControlRejected {}
+
+/// Nonces of the commands verified within the skew window. Older ones are
+/// forgotten, since their commands are refused as stale anyway.
+#[derive(Default)]
+struct SeenNonces(HashMap<(String, String), i64>);
+
+impl SeenNonces {
+    /// Records the nonce of a verified command. Returns `false` when it was
+    /// seen before.
+    fn insert(
+        &mut self,
+        cluster: &str,
+        nonce: &str,
+        sent_at: i64,
+        now: i64,
+        max_skew: u64,
+    ) -> bool {
+        self.0
+            .retain(|_, seen_at| now.abs_diff(*seen_at) <= max_skew);
+        match self.0.entry((cluster.to_string(), nonce.to_string())) {
+            Entry::Occupied(_) => false,
+            Entry::Vacant(entry) => {
+                entry.insert(sent_at);
+                true
+            }
+        }
+    }
+}
+
+/// Bytes HiveCore signs:
+/// `<timestamp>\n<nonce>\n<node_name>\n<method> <uri>\n<body>`, with the
+/// body trimmed. The node name keeps a command for one node from being
+/// replayed to another, and the nonce from being replayed to the same one.
+fn signed_payload(
+    timestamp: &str,
+    nonce: &str,
+    node_name: &str,
+    message: &ProxyMessage,
+) -> Vec<u8> {
+    let mut payload = format!(
+        "{timestamp}\n{nonce}\n{node_name}\n{} {}\n",
+        message.method, message.uri
+    )
+    .into_bytes();
+    payload.extend_from_slice(message.body.trim_ascii());
+    payload
+}
+
+/// Checks that a control command comes from HiveCore: an ed25519 signature
+/// when the cluster has `core_public_key`, otherwise an HMAC-SHA256 with the
+/// worker key. Only HIVE frames get here; a proxied `/worker/command`
+/// request is refused before.
+pub fn verify_control(message: &ProxyMessage, cluster: &ClusterConfig) -> Result<()> {
+    let config = get_control_signing()?;
+    let node_name = cluster_node_name(&cluster.name).unwrap_or_default();
+    verify_signature(
+        message,
+        cluster,
+        &node_name,
+        config.signing,
+        config.max_skew,
+        Utc::now().timestamp(),
+        &mut SEEN_NONCES.lock().unwrap(),
+    )
+    .map_err(Into::into)
+}
+
+fn verify_signature(
+    message: &ProxyMessage,
+    cluster: &ClusterConfig,
+    node_name: &str,
+    signing: ControlSigning,
+    max_skew: u64,
+    now: i64,
+    seen: &mut SeenNonces,
+) -> Result<(), ControlRejected> {
+    let (timestamp, nonce, signature) = match (
+        message.header(TIMESTAMP_HEADER),
+        message.header(NONCE_HEADER),
+        message.header(SIGNATURE_HEADER),
+    ) {
+        _ if signing == ControlSigning::Off && message.protocol == "HIVE" => return Ok(()),
+        (Some(timestamp), Some(nonce), Some(signature)) if !nonce.trim().is_empty() => {
+            (timestamp.trim(), nonce.trim(), signature.trim())
+        }
+        _ => return Err(ControlRejected::Unsigned),
+    };
+
+    let sent_at = timestamp
+        .parse::<i64>()
+        .map_err(|_| ControlRejected::BadSignature)?;
+    let skew = now.abs_diff(sent_at);
+    if skew > max_skew {
+        return Err(ControlRejected::Stale(skew));
+    }
+
+    let signature = hex::decode(signature).map_err(|_| ControlRejected::BadSignature)?;
+    let payload = signed_payload(timestamp, nonce, node_name, message);
+    let verified = match &cluster.core_public_key {
+        Some(public_key) => hex::decode(public_key.trim()).is_ok_and(|public_key| {
+            UnparsedPublicKey::new(&ED25519, public_key)
+                .verify(&payload, &signature)
+                .is_ok()
+        }),
+        None => {
+            let mut mac = HmacSha256::new_from_slice(cluster.key.as_bytes())
+                .expect("HMAC accepts keys of any length");
+            mac.update(&payload);
+            mac.verify_slice(&signature).is_ok()
+        }
+    };
+    if !verified {
+        return Err(ControlRejected::BadSignature);
+    }
+    // Only verified nonces are kept, so forged commands cannot use them up.
+    if !seen.insert(&cluster.name, nonce, sent_at, now, max_skew) {
+        return Err(ControlRejected::Replayed);
+    }
+    Ok(())
+}
+
+/// Records a refused control command in the log and in InfluxDB.
+pub fn audit_rejection(
+    message: &ProxyMessage,
+    cluster: &ClusterConfig,
+    command: &str,
+    rejected: &ControlRejected,
+) {
+    warn!(
+        "AUDIT: refused {} from cluster {} ({} {} {}): {}",
+        command, cluster.name, message.method, message.uri, message.protocol, rejected
+    );
+    let data_point = DataPoint::builder("control_audit")
+        .tag("cluster", cluster.name.clone())
+        .tag("command", command.to_string())
+        .tag("reason", rejected.reason())
+        .tag("protocol", message.protocol.clone())
+        .field("message", rejected.to_string());
+    log_influx(vec![data_point]);
+}
+
+#[cfg(test)]
+mod tests {
+    use hmac::Mac;
+
+    use super::{
+        signed_payload, verify_signature, ControlRejected, ControlSigning, HmacSha256, SeenNonces,
+        NONCE_HEADER, SIGNATURE_HEADER, TIMESTAMP_HEADER,
+    };
+    use crate::messages::proxy_message::ProxyMessage;
+    use crate::protocol::cluster::ClusterConfig;
+
+    fn cluster() -> ClusterConfig {
+        ClusterConfig {
+            name: "default".into(),
+            core_url: "core:7777".into(),
+            key: "worker-secret".into(),
+            concurrency: 1,
+            models: None,
+            core_public_key: None,
+        }
+    }
+
+    fn signed(raw: &str, timestamp: i64, nonce: &str, key: &str) -> ProxyMessage {
+        let mut message = ProxyMessage::parse(raw.as_bytes()).unwrap();
+        let payload = signed_payload(&timestamp.to_string(), nonce, "node-1", &message);
+        let mut mac = HmacSha256::new_from_slice(key.as_bytes()).unwrap();
+        mac.update(&payload);
+        let signature = hex::encode(mac.finalize().into_bytes());
+        message.headers.set(TIMESTAMP_HEADER, timestamp.to_string());
+        message.headers.set(NONCE_HEADER, nonce);
+        message.headers.set(SIGNATURE_HEADER, signature);
+        message
+    }
+
+    fn verify(
+        message: &ProxyMessage,
+        now: i64,
+        seen: &mut SeenNonces,
+    ) -> Result<(), ControlRejected> {
+        verify_signature(
+            message,
+            &cluster(),
+            "node-1",
+            ControlSigning::Required,
+            300,
+            now,
+            seen,
+        )
+    }
+
+    #[test]
+    fn accepts_commands_signed_with_the_worker_key() {
+        let seen = &mut SeenNonces::default();
+        let message = signed("REBOOT / HIVE\r\n\r\n", 1_000, "n-1", "worker-secret");
+
+        assert_eq!(verify(&message, 1_010, seen), Ok(()));
+        assert_eq!(
+            verify(&message, 2_000, seen),
+            Err(ControlRejected::Stale(1_000))
+        );
+
+        let forged = signed("REBOOT / HIVE\r\n\r\n", 1_000, "n-2", "guessed");
+        assert_eq!(
+            verify(&forged, 1_000, seen),
+            Err(ControlRejected::BadSignature)
+        );
+
+        let mut tampered = signed("REBOOT / HIVE\r\n\r\n", 1_000, "n-3", "worker-secret");
+        tampered.method = "SHUTDOWN".into();
+        assert_eq!(
+            verify(&tampered, 1_000, seen),
+            Err(ControlRejected::BadSignature)
+        );
+    }
+
+    #[test]
+    fn requires_signatures_on_the_http_command_path() {
+        let hive = ProxyMessage::parse(b"REBOOT / HIVE\r\n\r\n").unwrap();
+        let http = ProxyMessage::parse(b"POST /worker/command HTTP/1.1\r\n\r\nREBOOT").unwrap();
+        let verify = |message: &ProxyMessage, signing| {
+            verify_signature(
+                message,
+                &cluster(),
+                "node-1",
+                signing,
+                300,
+                0,
+                &mut SeenNonces::default(),
+            )
+        };
+
+        assert_eq!(
+            verify(&hive, ControlSigning::Required),
+            Err(ControlRejected::Unsigned)
+        );
+        assert_eq!(verify(&hive, ControlSigning::Off), Ok(()));
+        assert_eq!(
+            verify(&http, ControlSigning::Off),
+            Err(ControlRejected::Unsigned)
+        );
+    }
+
+    #[test]
+    fn rejects_replayed_commands() {
+        let seen = &mut SeenNonces::default();
+        let message = signed("SHUTDOWN / HIVE\r\n\r\n", 1_000, "n-1", "worker-secret");
+        assert_eq!(verify(&message, 1_000, seen), Ok(()));
+        assert_eq!(
+            verify(&message, 1_100, seen),
+            Err(ControlRejected::Replayed)
+        );
+
+        // A forged command does not use up the nonce it names.
+        let forged = signed("SHUTDOWN / HIVE\r\n\r\n", 1_000, "n-2", "guessed");
+        assert_eq!(
+            verify(&forged, 1_000, seen),
+            Err(ControlRejected::BadSignature)
+        );
+        let next = signed("SHUTDOWN / HIVE\r\n\r\n", 1_000, "n-2", "worker-secret");
+        assert_eq!(verify(&next, 1_000, seen), Ok(()));
+
+        // Nonces are forgotten once their commands would be stale.
+        verify(
+            &signed("PAUSE / HIVE\r\n\r\n", 2_000, "n-3", "worker-secret"),
+            2_000,
+            seen,
+        )
+        .unwrap();
+        assert_eq!(seen.0.len(), 1);
+
+        let unsigned_nonce = {
+            let mut message = signed("PAUSE / HIVE\r\n\r\n", 2_000, "n-4", "worker-secret");
+            message.headers.remove(NONCE_HEADER);
+            message
+        };
+        assert_eq!(
+            verify(&unsigned_nonce, 2_000, seen),
+            Err(ControlRejected::Unsigned)
+        );
+    }
+}
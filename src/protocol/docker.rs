@@ -12,10 +12,13 @@ use indicatif::{ProgressBar, ProgressDrawTarget, ProgressStyle};
 use log::{error, info, warn};
 use nvml_wrapper::Nvml;
 use once_cell::sync::Lazy;
-use reqwest::blocking::Client;
-use std::{collections::HashMap, env, sync::RwLock, time::Duration};
+use reqwest::Client;
+use std::{collections::HashMap, env, time::Duration};
+use tokio::sync::RwLock;
 use tokio::time::sleep;
 
+use super::cluster::primary_key;
+
 pub static DOCKER_UPGRADE_LOCK: Lazy<RwLock<()>> = Lazy::new(|| RwLock::new(()));
 
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
@@ -34,6 +37,13 @@ impl OllamaMode {
             )),
         }
     }
+
+    pub fn label(self) -> &'static str {
+        match self {
+            Self::Docker => "docker",
+            Self::External => "external",
+        }
+    }
 }
 
 pub fn get_ollama_mode() -> Result<OllamaMode> {
@@ -66,15 +76,13 @@ pub async fn configure_ollama_runtime() -> Result<()> {
     Ok(())
 }
 
-pub fn configure_ollama_runtime_blocking() -> Result<()> {
+pub async fn ensure_ollama_runtime() -> Result<()> {
     match get_ollama_mode()? {
         OllamaMode::Docker => {
             // Serialize Docker-backed runtime reconciliation so concurrent worker
-            // threads do not all try to recreate the same container at once.
-            let _write_guard = DOCKER_UPGRADE_LOCK.write().unwrap();
-            let rt = tokio::runtime::Runtime::new()
-                .context("Failed to create Tokio runtime for Ollama startup")?;
-            rt.block_on(configure_ollama_runtime())
+            // connections do not all try to recreate the same container at once.
+            let _write_guard = DOCKER_UPGRADE_LOCK.write().await;
+            configure_ollama_runtime().await
         }
         OllamaMode::External => {
             let ollama_url =
@@ -108,7 +116,7 @@ async fn wait_for_ollama_http_ready(
     let client = Client::new();
 
     for attempt in 1..=attempts {
-        match client.get(format!("{base_url}/api/version")).send() {
+        match client.get(format!("{base_url}/api/version")).send().await {
             Ok(response) if response.status().is_success() => {
                 info!("Ollama API is ready at {base_url}");
                 return Ok(());
@@ -135,7 +143,7 @@ async fn wait_for_ollama_http_ready(
 pub async fn start_ollama_docker() -> anyhow::Result<String> {
     let models_dir =
         env::var("HIVE_OLLAMA_MODELS").context("HIVE_OLLAMA_MODELS must be set in docker mode")?;
-    let key = env::var("HIVE_KEY").context("HIVE_KEY must be set")?;
+    let key = primary_key()?;
     let port = env::var("OLLAMA_PORT").context("OLLAMA_PORT must be set in docker mode")?;
     let ollama_url = format!("http://127.0.0.1:{port}");
 
@@ -235,7 +243,7 @@ pub async fn start_ollama_docker() -> anyhow::Result<String> {
     pb_pull.finish_with_message("Image pulled");
     let mut port_bindings = HashMap::new();
     port_bindings.insert(
-        format!("11434/tcp"),
+        "11434/tcp".to_string(),
         Some(vec![PortBinding {
             host_ip: Some("0.0.0.0".to_string()),
             host_port: Some(port.to_string()),
@@ -250,7 +258,7 @@ pub async fn start_ollama_docker() -> anyhow::Result<String> {
         device_requests,
         ..Default::default()
     };
-    let bind = format!("11434/tcp");
+    let bind = "11434/tcp".to_string();
     let create_opts = Config {
         image: Some("ollama/ollama:latest"),
         host_config: Some(host_config),
@@ -284,10 +292,13 @@ pub async fn start_ollama_docker() -> anyhow::Result<String> {
     Ok(id)
 }
 
+// The upgrade deliberately keeps the write guard across the container swap so
+// worker connections cannot proxy requests to a container that is being
+// replaced.
 pub async fn upgrade_ollama_docker() -> Result<String> {
     let models_dir =
         env::var("HIVE_OLLAMA_MODELS").context("HIVE_OLLAMA_MODELS must be set in docker mode")?;
-    let key = env::var("HIVE_KEY").context("HIVE_KEY must be set")?;
+    let key = primary_key()?;
     let port = env::var("OLLAMA_PORT").context("OLLAMA_PORT must be set in docker mode")?;
     let ollama_url = env::var("OLLAMA_URL").context("OLLAMA_URL must be set before upgrade")?;
     let container_name = format!("ollama-hive-{}", &key[..5]);
@@ -343,7 +354,7 @@ pub async fn upgrade_ollama_docker() -> Result<String> {
     info!("Stopping Docker container");
 
     warn!("Waiting to gain control of the docker connection from other threads");
-    let _write_guard = DOCKER_UPGRADE_LOCK.write().unwrap();
+    let _write_guard = DOCKER_UPGRADE_LOCK.write().await;
     warn!("Got control!");
 
     match docker
@@ -391,7 +402,7 @@ pub async fn upgrade_ollama_docker() -> Result<String> {
     }
 
     let mut port_bindings = HashMap::new();
-    let bind = format!("11434/tcp");
+    let bind = "11434/tcp".to_string();
     port_bindings.insert(
         bind.clone(),
         Some(vec![PortBinding {
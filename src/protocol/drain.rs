@@ -0,0 +1,62 @@
+use anyhow::Result;
+use log::{error, warn};
+use std::time::Duration;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio::time::sleep;
+
+use super::env_util::env_secs;
+use super::state::{get_shutdown, set_shutdown, shutdown_requested};
+
+/// Line sent to HiveCore when a connection stops taking work because the node
+/// is shutting down.
+pub const DRAINING_MESSAGE: &str = "DRAINING / HIVE\r\n";
+
+/// Exit code used when the drain deadline cut off running jobs.
+pub const DRAIN_TIMEOUT_EXIT_CODE: i32 = 1;
+
+/// How long running jobs may take to finish once a drain starts; `None`
+/// waits for them indefinitely.
+pub fn drain_timeout() -> Result<Option<Duration>> {
+    env_secs("HIVE_DRAIN_TIMEOUT_SECS", 120)
+}
+
+/// Starts a drain on SIGTERM or SIGINT. A second signal exits right away.
+pub async fn drain_on_signals() -> Result<()> {
+    #[cfg(unix)]
+    let mut terminate = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())?;
+    loop {
+        #[cfg(unix)]
+        let signal = tokio::select! {
+            _ = tokio::signal::ctrl_c() => "SIGINT",
+            _ = terminate.recv() => "SIGTERM",
+        };
+        #[cfg(not(unix))]
+        let signal = {
+            tokio::signal::ctrl_c().await?;
+            "SIGINT"
+        };
+
+        if get_shutdown() {
+            error!("Received {} while draining, exiting now", signal);
+            std::process::exit(DRAIN_TIMEOUT_EXIT_CODE);
+        }
+        warn!("Received {}, draining before exit", signal);
+        set_shutdown(true);
+    }
+}
+
+/// Resolves once a drain has been running for longer than `timeout`.
+pub async fn drain_deadline(timeout: Option<Duration>) {
+    shutdown_requested().await;
+    match timeout {
+        Some(timeout) => sleep(timeout).await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Tells HiveCore this connection takes no more work.
+pub async fn announce_drain<W: AsyncWrite + Unpin>(stream: &mut W) -> Result<()> {
+    stream.write_all(DRAINING_MESSAGE.as_bytes()).await?;
+    stream.flush().await?;
+    Ok(())
+}
@@ -0,0 +1,197 @@
+use anyhow::{anyhow, Result};
+use log::{info, warn};
+use std::time::Duration;
+use tokio::time::{sleep_until, Instant};
+
+use super::env_util::{env_secs, env_u64};
+use super::transport::{connect_core, connect_tcp, core_uses_tls, CoreStream};
+
+/// One HiveCore address from `HIVE_CORE_URL`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct CoreEndpoint {
+    pub addr: String,
+    pub tls: bool,
+}
+
+/// Parses a comma-separated `HIVE_CORE_URL`. The order is the priority: the
+/// first endpoint is preferred, the rest are standbys.
+pub fn parse_core_endpoints(raw: &str) -> Result<Vec<CoreEndpoint>> {
+    let endpoints = raw
+        .split(',')
+        .map(str::trim)
+        .filter(|url| !url.is_empty())
+        .map(|url| {
+            Ok(CoreEndpoint {
+                addr: normalize_core_tcp_addr(url)?,
+                tls: core_uses_tls(url),
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    if endpoints.is_empty() {
+        return Err(anyhow!("HIVE_CORE_URL must include host:port"));
+    }
+    Ok(endpoints)
+}
+
+fn normalize_core_tcp_addr(raw_url: &str) -> Result<String> {
+    let trimmed = raw_url.trim();
+    let without_scheme = trimmed
+        .strip_prefix("http://")
+        .or_else(|| trimmed.strip_prefix("https://"))
+        .or_else(|| trimmed.strip_prefix("tls://"))
+        .unwrap_or(trimmed);
+    let addr = without_scheme
+        .split('/')
+        .next()
+        .unwrap_or("")
+        .trim()
+        .to_string();
+
+    if addr.is_empty() {
+        return Err(anyhow!("HIVE_CORE_URL must include host:port"));
+    }
+
+    Ok(addr)
+}
+
+#[derive(Clone, Debug)]
+pub struct EndpointConfig {
+    pub connect_timeout: Duration,
+    /// How often a session on a standby checks whether a preferred endpoint is
+    /// back. `None` disables failback.
+    pub failback_interval: Option<Duration>,
+}
+
+impl EndpointConfig {
+    pub fn from_env() -> Result<Self> {
+        Ok(Self {
+            connect_timeout: Duration::from_secs(
+                env_u64("HIVE_CORE_CONNECT_TIMEOUT_SECS", 10)?.max(1),
+            ),
+            failback_interval: env_secs("HIVE_CORE_FAILBACK_SECS", 300)?,
+        })
+    }
+}
+
+/// Connects to the highest priority endpoint that is reachable and returns its
+/// index together with the stream.
+pub async fn connect_preferred(
+    endpoints: &[CoreEndpoint],
+    config: &EndpointConfig,
+) -> Result<(usize, CoreStream)> {
+    let mut last_error = None;
+    for (index, endpoint) in endpoints.iter().enumerate() {
+        match connect_core(&endpoint.addr, endpoint.tls, config.connect_timeout).await {
+            Ok(stream) => {
+                if index > 0 {
+                    warn!(
+                        "Failed over to standby HiveCore {} (priority {})",
+                        endpoint.addr,
+                        index + 1
+                    );
+                }
+                return Ok((index, stream));
+            }
+            Err(e) => {
+                warn!(
+                    "HiveCore endpoint {} is unreachable: {:#}",
+                    endpoint.addr, e
+                );
+                last_error = Some(e);
+            }
+        }
+    }
+
+    Err(last_error
+        .unwrap_or_else(|| anyhow!("No HiveCore endpoints configured"))
+        .context("No HiveCore endpoint is reachable"))
+}
+
+/// Periodically probes the endpoints that rank above the active one.
+pub struct Failback {
+    endpoints: Vec<CoreEndpoint>,
+    config: EndpointConfig,
+    active: usize,
+    next_check: Instant,
+}
+
+impl Failback {
+    pub fn new(endpoints: Vec<CoreEndpoint>, active: usize, config: EndpointConfig) -> Self {
+        Self {
+            next_check: Instant::now() + config.failback_interval.unwrap_or_default(),
+            endpoints,
+            config,
+            active,
+        }
+    }
+
+    /// Resolves with a preferred endpoint once it accepts TCP connections
+    /// again. Never resolves on the preferred endpoint or with failback off.
+    ///
+    /// Cancel safe: the schedule lives in `self`, so the session can race this
+    /// against reading the next message.
+    pub async fn preferred_available(&mut self) -> &CoreEndpoint {
+        let interval = match self.config.failback_interval {
+            Some(interval) if self.active > 0 => interval,
+            _ => return std::future::pending().await,
+        };
+
+        loop {
+            sleep_until(self.next_check).await;
+            self.next_check = Instant::now() + interval;
+
+            for endpoint in &self.endpoints[..self.active] {
+                if connect_tcp(&endpoint.addr, self.config.connect_timeout)
+                    .await
+                    .is_ok()
+                {
+                    info!("Preferred HiveCore {} is reachable again", endpoint.addr);
+                    return endpoint;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{normalize_core_tcp_addr, parse_core_endpoints, CoreEndpoint};
+
+    #[test]
+    fn normalizes_core_tcp_addr() {
+        assert_eq!(
+            normalize_core_tcp_addr("http://hivecore.example.com:7777").unwrap(),
+            "hivecore.example.com:7777"
+        );
+        assert_eq!(
+            normalize_core_tcp_addr("tls://hivecore.example.com:7777/").unwrap(),
+            "hivecore.example.com:7777"
+        );
+        assert_eq!(
+            normalize_core_tcp_addr("127.0.0.1:7777").unwrap(),
+            "127.0.0.1:7777"
+        );
+    }
+
+    #[test]
+    fn parses_endpoint_list_in_priority_order() {
+        let endpoints =
+            parse_core_endpoints("tls://primary.example.com:7777, standby.example.com:7777,")
+                .unwrap();
+        assert_eq!(
+            endpoints,
+            vec![
+                CoreEndpoint {
+                    addr: "primary.example.com:7777".into(),
+                    tls: true,
+                },
+                CoreEndpoint {
+                    addr: "standby.example.com:7777".into(),
+                    tls: false,
+                },
+            ]
+        );
+        assert!(parse_core_endpoints(" , ").is_err());
+    }
+}
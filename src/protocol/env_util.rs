@@ -0,0 +1,37 @@
+use anyhow::{Context, Result};
+use std::env;
+use std::time::Duration;
+
+pub fn env_flag(name: &str) -> bool {
+    env_bool(name, false)
+}
+
+/// Reads an on/off setting, falling back to `default` when unset or empty.
+pub fn env_bool(name: &str, default: bool) -> bool {
+    match env::var(name) {
+        Ok(value) if !value.trim().is_empty() => matches!(
+            value.trim().to_ascii_lowercase().as_str(),
+            "1" | "true" | "yes" | "on"
+        ),
+        _ => default,
+    }
+}
+
+/// Reads a whole number setting, falling back to `default` when unset or empty.
+pub fn env_u64(name: &str, default: u64) -> Result<u64> {
+    match env::var(name) {
+        Ok(value) if !value.trim().is_empty() => value
+            .trim()
+            .parse::<u64>()
+            .with_context(|| format!("{name} must be a whole number")),
+        _ => Ok(default),
+    }
+}
+
+/// Reads a duration in seconds where `0` disables the feature.
+pub fn env_secs(name: &str, default: u64) -> Result<Option<Duration>> {
+    Ok(match env_u64(name, default)? {
+        0 => None,
+        secs => Some(Duration::from_secs(secs)),
+    })
+}
@@ -0,0 +1,205 @@
+use anyhow::Result;
+use chrono::Utc;
+use lazy_static::lazy_static;
+use log::{info, warn};
+use reqwest::Client;
+use serde::Serialize;
+use std::collections::{HashMap, VecDeque};
+use std::future::Future;
+use std::sync::Mutex;
+use std::time::Instant;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+
+use super::backend::backend_version;
+use super::mux::MuxFrame;
+use crate::messages::proxy_message::{Headers, ProxyMessage};
+
+lazy_static! {
+    /// Queued events by the name of the cluster that sent the command.
+    static ref PENDING_EVENTS: Mutex<HashMap<String, VecDeque<CommandEvent>>> =
+        Mutex::new(HashMap::new());
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CommandOutcome {
+    Succeeded,
+    Failed,
+}
+
+/// The final outcome of a command that was acknowledged with `202 Accepted`
+/// and carried on in the background.
+#[derive(Clone, Debug, Serialize)]
+pub struct CommandEvent {
+    pub command: String,
+    pub request_id: Option<String>,
+    pub outcome: CommandOutcome,
+    pub error: Option<String>,
+    pub started_at: String,
+    pub duration_ms: u64,
+    pub old_backend_version: String,
+    pub new_backend_version: String,
+    pub old_node_version: String,
+    pub new_node_version: String,
+}
+
+impl CommandEvent {
+    pub fn message(&self) -> Result<String> {
+        let mut message = ProxyMessage {
+            protocol: "HIVE".into(),
+            method: "EVENT".into(),
+            uri: "/".into(),
+            headers: Headers::default(),
+            body: Vec::new(),
+        };
+        message.set_json(self)?;
+        Ok(String::from_utf8(message.to_bytes())?)
+    }
+}
+
+/// Runs a background command and queues its outcome, together with the
+/// backend versions before and after, for the next connection to `cluster`
+/// to report.
+pub async fn report_completion<F, T>(
+    command: String,
+    request_id: Option<String>,
+    cluster: String,
+    client: Client,
+    work: F,
+) -> Result<T>
+where
+    F: Future<Output = Result<T>>,
+{
+    let started_at = Utc::now().to_rfc3339();
+    let started = Instant::now();
+    let old_backend_version = backend_version(&client).await;
+    let result = work.await;
+    let (outcome, error) = match &result {
+        Ok(_) => (CommandOutcome::Succeeded, None),
+        Err(e) => (CommandOutcome::Failed, Some(format!("{e:#}"))),
+    };
+    queue_event(
+        &cluster,
+        CommandEvent {
+            command,
+            request_id,
+            outcome,
+            error,
+            started_at,
+            duration_ms: started.elapsed().as_millis() as u64,
+            old_backend_version,
+            new_backend_version: backend_version(&client).await,
+            old_node_version: env!("CARGO_PKG_VERSION").to_string(),
+            new_node_version: env!("CARGO_PKG_VERSION").to_string(),
+        },
+    );
+    result
+}
+
+/// Queues an event for the cluster whose HiveCore sent the command, so it
+/// never reaches another cluster.
+pub fn queue_event(cluster: &str, event: CommandEvent) {
+    PENDING_EVENTS
+        .lock()
+        .unwrap()
+        .entry(cluster.to_string())
+        .or_default()
+        .push_back(event);
+}
+
+fn next_event(cluster: &str) -> Option<CommandEvent> {
+    PENDING_EVENTS.lock().unwrap().get_mut(cluster)?.pop_front()
+}
+
+/// Sends `cluster`'s queued events on a freshly authenticated connection to
+/// it, as `CONTROL` frames when it is multiplexed. Events that could not be
+/// sent stay queued for the next connection.
+pub async fn send_pending_events<W: AsyncWrite + Unpin>(
+    stream: &mut W,
+    cluster: &str,
+    multiplexed: bool,
+) -> Result<()> {
+    loop {
+        let Some(event) = next_event(cluster) else {
+            return Ok(());
+        };
+        let message = event.message()?;
+        let bytes = if multiplexed {
+            MuxFrame::control(message).encode()
+        } else {
+            message.into_bytes()
+        };
+        let sent = async {
+            stream.write_all(&bytes).await?;
+            stream.flush().await
+        };
+        if let Err(e) = sent.await {
+            warn!("Could not report {} to HiveCore: {}", event.command, e);
+            PENDING_EVENTS
+                .lock()
+                .unwrap()
+                .entry(cluster.to_string())
+                .or_default()
+                .push_front(event);
+            return Err(e.into());
+        }
+        info!("Reported {:?} {} to HiveCore", event.outcome, event.command);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{queue_event, send_pending_events, CommandEvent, CommandOutcome};
+
+    fn event() -> CommandEvent {
+        CommandEvent {
+            command: "UPDATE_OLLAMA".into(),
+            request_id: Some("c-42".into()),
+            outcome: CommandOutcome::Failed,
+            error: Some("pull failed".into()),
+            started_at: "2026-10-18T10:00:00+00:00".into(),
+            duration_ms: 1500,
+            old_backend_version: "0.9.0".into(),
+            new_backend_version: "0.9.0".into(),
+            old_node_version: "0.1.9".into(),
+            new_node_version: "0.1.9".into(),
+        }
+    }
+
+    #[test]
+    fn frames_events_as_json() {
+        let message = event().message().unwrap();
+        let (head, body) = message.split_once("\r\n\r\n").unwrap();
+
+        assert!(head.starts_with("EVENT / HIVE\r\n"));
+        assert!(head.ends_with(&format!("Content-Length: {}", body.len())));
+        let body: serde_json::Value = serde_json::from_str(body).unwrap();
+        assert_eq!(body["outcome"], "failed");
+        assert_eq!(body["request_id"], "c-42");
+    }
+
+    #[tokio::test]
+    async fn reports_events_only_to_their_cluster() {
+        queue_event("events-a", event());
+
+        let mut to_b = Vec::new();
+        send_pending_events(&mut to_b, "events-b", false)
+            .await
+            .unwrap();
+        assert!(to_b.is_empty());
+
+        let mut to_a = Vec::new();
+        send_pending_events(&mut to_a, "events-a", false)
+            .await
+            .unwrap();
+        assert!(String::from_utf8(to_a)
+            .unwrap()
+            .contains("\"request_id\":\"c-42\""));
+
+        let mut to_b = Vec::new();
+        send_pending_events(&mut to_b, "events-b", false)
+            .await
+            .unwrap();
+        assert!(to_b.is_empty());
+    }
+}
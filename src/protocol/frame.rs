@@ -0,0 +1,227 @@
+use anyhow::{anyhow, Result};
+use std::fmt::{Display, Formatter};
+use std::io;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use super::env_util::env_u64;
+
+const DEFAULT_MAX_FRAME_BYTES: u64 = 64 * 1024 * 1024;
+/// Most payload bytes read at once. The buffer grows as data arrives, so a
+/// large length prefix alone does not allocate its full size.
+const READ_CHUNK_BYTES: usize = 64 * 1024;
+/// Leading bytes kept of a frame that is too large, enough to tell which
+/// request or stream it was. Never more than the limit itself.
+const HEAD_BYTES: usize = 256;
+
+/// The largest frame HiveCore may send, `HIVE_MAX_FRAME_BYTES`.
+pub fn max_frame_bytes() -> Result<usize> {
+    match env_u64("HIVE_MAX_FRAME_BYTES", DEFAULT_MAX_FRAME_BYTES)? {
+        0 => Err(anyhow!("HIVE_MAX_FRAME_BYTES must be at least 1")),
+        max => Ok(usize::try_from(max).unwrap_or(usize::MAX)),
+    }
+}
+
+/// A frame the node refused to read.
+#[derive(Debug)]
+pub enum FrameError {
+    /// The length prefix is negative. The rest of the connection cannot be
+    /// read, since where the next frame starts is unknown.
+    NegativeLength(i32),
+    /// The frame is longer than `max`. It was skipped, apart from its first
+    /// bytes in `head`, so the connection can go on.
+    TooLarge {
+        length: usize,
+        max: usize,
+        head: Vec<u8>,
+    },
+}
+
+impl FrameError {
+    pub fn reason(&self) -> &'static str {
+        match self {
+            Self::NegativeLength(_) => "negative_length",
+            Self::TooLarge { .. } => "frame_too_large",
+        }
+    }
+
+    /// Tells HiveCore why its frame was refused.
+    pub fn message(&self) -> String {
+        protocol_error(self.reason(), &self.to_string())
+    }
+}
+
+/// A `PROTOCOL-ERROR` message telling HiveCore why something it sent was
+/// refused.
+pub fn protocol_error(reason: &str, detail: &str) -> String {
+    let body = format!("{detail}.\n");
+    format!(
+        "PROTOCOL-ERROR {} HIVE\r\nContent-Type: text/plain\r\nContent-Length: {}\r\n\r\n{}",
+        reason,
+        body.len(),
+        body
+    )
+}
+
+impl Display for FrameError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::NegativeLength(length) => write!(f, "Frame length {length} is negative"),
+            Self::TooLarge { length, max, .. } => write!(
+                f,
+                "Frame of {length} bytes is larger than the maximum of {max} bytes"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for FrameError {}
+
+/// Reads the length-prefixed frames HiveCore sends: a 4-byte big-endian length
+/// followed by that many payload bytes.
+///
+/// The partially read frame is kept in the reader, so `read_frame` is cancel
+/// safe: a `read_frame` future dropped by `select!` or a timeout loses no
+/// bytes, and the next call continues where it stopped.
+pub struct FrameReader {
+    max_bytes: usize,
+    header: [u8; 4],
+    header_filled: usize,
+    payload: Vec<u8>,
+    /// Bytes of a frame that is too large that were read and dropped.
+    skipped: usize,
+    progressed: bool,
+}
+
+impl Default for FrameReader {
+    fn default() -> Self {
+        Self::new(DEFAULT_MAX_FRAME_BYTES as usize)
+    }
+}
+
+impl FrameReader {
+    pub fn new(max_bytes: usize) -> Self {
+        Self {
+            max_bytes,
+            header: [0; 4],
+            header_filled: 0,
+            payload: Vec::new(),
+            skipped: 0,
+            progressed: false,
+        }
+    }
+
+    /// Reads the next frame. A frame longer than the maximum is read to its
+    /// end without being kept and fails with [`FrameError::TooLarge`]; a
+    /// negative length fails with [`FrameError::NegativeLength`].
+    pub async fn read_frame<R: AsyncRead + Unpin>(&mut self, stream: &mut R) -> Result<Vec<u8>> {
+        while self.header_filled < self.header.len() {
+            let n = stream.read(&mut self.header[self.header_filled..]).await?;
+            if n == 0 {
+                return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+            }
+            self.header_filled += n;
+            self.progressed = true;
+        }
+
+        let length = i32::from_be_bytes(self.header);
+        let length = usize::try_from(length).map_err(|_| FrameError::NegativeLength(length))?;
+        let too_large = length > self.max_bytes;
+        let kept = if too_large {
+            length.min(HEAD_BYTES).min(self.max_bytes)
+        } else {
+            length
+        };
+
+        while self.payload.len() < kept {
+            let wanted = (kept - self.payload.len()).min(READ_CHUNK_BYTES);
+            self.payload.reserve(wanted);
+            let n = (&mut *stream)
+                .take(wanted as u64)
+                .read_buf(&mut self.payload)
+                .await?;
+            if n == 0 {
+                return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+            }
+            self.progressed = true;
+        }
+
+        let mut discard = [0u8; 8 * 1024];
+        while kept + self.skipped < length {
+            let wanted = (length - kept - self.skipped).min(discard.len());
+            let n = stream.read(&mut discard[..wanted]).await?;
+            if n == 0 {
+                return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+            }
+            self.skipped += n;
+            self.progressed = true;
+        }
+
+        let frame = std::mem::take(&mut self.payload);
+        self.header_filled = 0;
+        self.skipped = 0;
+        if too_large {
+            return Err(FrameError::TooLarge {
+                length,
+                max: self.max_bytes,
+                head: frame,
+            }
+            .into());
+        }
+        Ok(frame)
+    }
+
+    /// Whether any bytes arrived since the last call.
+    pub fn take_progress(&mut self) -> bool {
+        std::mem::take(&mut self.progressed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{FrameError, FrameReader};
+    use std::time::Duration;
+    use tokio::io::AsyncWriteExt;
+    use tokio::time::timeout;
+
+    #[tokio::test]
+    async fn keeps_partial_frame_when_read_is_cancelled() {
+        let (mut core, mut node) = tokio::io::duplex(64);
+        let mut reader = FrameReader::default();
+
+        core.write_all(&[0, 0, 0, 5, b'P', b'I']).await.unwrap();
+        assert!(
+            timeout(Duration::from_millis(20), reader.read_frame(&mut node))
+                .await
+                .is_err()
+        );
+        assert!(reader.take_progress());
+
+        core.write_all(b"NG!").await.unwrap();
+        let frame = reader.read_frame(&mut node).await.unwrap();
+        assert_eq!(frame, b"PING!");
+    }
+
+    #[tokio::test]
+    async fn skips_frames_over_the_limit() {
+        let (mut core, mut node) = tokio::io::duplex(64);
+        let mut reader = FrameReader::new(8);
+
+        let mut large = 1000i32.to_be_bytes().to_vec();
+        large.extend(std::iter::repeat_n(b'x', 1000));
+        large.extend([0, 0, 0, 2, b'o', b'k']);
+        large.extend((-1i32).to_be_bytes());
+        tokio::spawn(async move { core.write_all(&large).await });
+
+        let error = reader.read_frame(&mut node).await.unwrap_err();
+        assert!(matches!(
+            error.downcast_ref(),
+            Some(FrameError::TooLarge { length: 1000, max: 8, head }) if head == b"xxxxxxxx"
+        ));
+        assert_eq!(reader.read_frame(&mut node).await.unwrap(), b"ok");
+        let error = reader.read_frame(&mut node).await.unwrap_err();
+        assert!(matches!(
+            error.downcast_ref(),
+            Some(FrameError::NegativeLength(-1))
+        ));
+    }
+}
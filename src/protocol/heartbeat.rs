@@ -0,0 +1,239 @@
+use anyhow::{anyhow, Result};
+use log::{debug, error, warn};
+use socket2::{SockRef, TcpKeepalive};
+use std::time::{Duration, Instant};
+use tokio::io::AsyncRead;
+use tokio::time::timeout;
+
+use crate::messages::proxy_message::ProxyMessage;
+
+use super::env_util::{env_secs, env_u64};
+use super::frame::FrameReader;
+use super::transport::CoreStream;
+
+#[derive(Clone, Debug)]
+pub struct HeartbeatConfig {
+    /// How long the connection may stay silent before a `PING` is sent.
+    /// `None` disables application-level pings.
+    pub interval: Option<Duration>,
+    /// How long to wait for any traffic after a `PING` before giving up.
+    pub timeout: Duration,
+    pub write_timeout: Option<Duration>,
+    pub keepalive_idle: Option<Duration>,
+    pub keepalive_interval: Duration,
+    pub keepalive_retries: u32,
+}
+
+impl HeartbeatConfig {
+    pub fn from_env() -> Result<Self> {
+        Ok(Self {
+            interval: env_secs("HIVE_HEARTBEAT_INTERVAL_SECS", 30)?,
+            timeout: Duration::from_secs(env_u64("HIVE_HEARTBEAT_TIMEOUT_SECS", 10)?.max(1)),
+            write_timeout: env_secs("HIVE_SOCKET_WRITE_TIMEOUT_SECS", 60)?,
+            keepalive_idle: env_secs("HIVE_TCP_KEEPALIVE_SECS", 60)?,
+            keepalive_interval: Duration::from_secs(
+                env_u64("HIVE_TCP_KEEPALIVE_INTERVAL_SECS", 10)?.max(1),
+            ),
+            keepalive_retries: env_u64("HIVE_TCP_KEEPALIVE_RETRIES", 3)? as u32,
+        })
+    }
+
+    /// How long a read may wait before the heartbeat gets a chance to run.
+    fn read_tick(&self) -> Option<Duration> {
+        self.interval.map(|interval| interval.min(self.timeout))
+    }
+}
+
+/// Applies the write timeout and TCP keepalive to the HiveCore connection.
+pub fn configure_socket(stream: &mut CoreStream, config: &HeartbeatConfig) -> Result<()> {
+    stream.set_write_timeout(config.write_timeout);
+
+    if let Some(idle) = config.keepalive_idle {
+        let keepalive = TcpKeepalive::new()
+            .with_time(idle)
+            .with_interval(config.keepalive_interval)
+            .with_retries(config.keepalive_retries);
+        SockRef::from(stream.tcp()).set_tcp_keepalive(&keepalive)?;
+    }
+    Ok(())
+}
+
+/// Per-connection liveness tracking for the HiveCore stream.
+///
+/// Any inbound traffic counts as a sign of life. When the stream has been
+/// silent for `interval`, a `PING <seq> HIVE` is sent and HiveCore has
+/// `timeout` to answer before the connection is declared dead.
+pub struct Heartbeat {
+    config: HeartbeatConfig,
+    active: bool,
+    seq: u64,
+    last_seen: Instant,
+    outstanding: Option<(u64, Instant)>,
+}
+
+impl Heartbeat {
+    pub fn new(config: HeartbeatConfig) -> Self {
+        Self {
+            config,
+            active: false,
+            seq: 0,
+            last_seen: Instant::now(),
+            outstanding: None,
+        }
+    }
+
+    /// Starts sending pings. Until then (during the handshake) a silent peer
+    /// only fails once `interval + timeout` has passed.
+    pub fn start(&mut self) {
+        self.active = true;
+        self.last_seen = Instant::now();
+    }
+
+    pub fn read_tick(&self) -> Option<Duration> {
+        self.config.read_tick()
+    }
+
+    pub fn on_activity(&mut self) {
+        self.last_seen = Instant::now();
+        self.outstanding = None;
+    }
+
+    /// Whether `message` is the `PONG` answering our outstanding ping.
+    pub fn is_own_pong(&self, message: &ProxyMessage) -> bool {
+        message.protocol == "HIVE"
+            && message.method == "PONG"
+            && message.uri.parse::<u64>().ok() == Some(self.seq)
+    }
+
+    /// Reads the next frame from HiveCore while keeping the heartbeat
+    /// running. When a ping falls due before a frame arrives, the ping line is
+    /// returned so the caller can send it the way its session writes.
+    ///
+    /// Cancel safe, as long as a returned ping is sent.
+    pub async fn next_frame<R: AsyncRead + Unpin>(
+        &mut self,
+        stream: &mut R,
+        reader: &mut FrameReader,
+    ) -> Result<Inbound> {
+        loop {
+            let frame = match self.read_tick() {
+                Some(tick) => match timeout(tick, reader.read_frame(stream)).await {
+                    Ok(frame) => frame,
+                    Err(_) => {
+                        if reader.take_progress() {
+                            self.on_activity();
+                        } else if let Some(ping) = self.check_idle(Instant::now())? {
+                            return Ok(Inbound::PingDue(ping));
+                        }
+                        continue;
+                    }
+                },
+                None => reader.read_frame(stream).await,
+            };
+            let frame =
+                frame.inspect_err(|e| error!("Error reading message from HiveCore: {}", e))?;
+            reader.take_progress();
+            self.on_activity();
+            return Ok(Inbound::Frame(frame));
+        }
+    }
+
+    /// Returns the `PING` line to send when the connection has been idle for
+    /// `interval`, and fails once a ping went unanswered for `timeout`.
+    fn check_idle(&mut self, now: Instant) -> Result<Option<String>> {
+        let interval = match self.config.interval {
+            Some(interval) => interval,
+            None => return Ok(None),
+        };
+
+        if let Some((seq, sent_at)) = self.outstanding {
+            if now.duration_since(sent_at) >= self.config.timeout {
+                warn!("HiveCore did not answer PING {} in time", seq);
+                return Err(anyhow!(
+                    "Heartbeat timed out: no traffic from HiveCore for {}s",
+                    now.duration_since(self.last_seen).as_secs()
+                ));
+            }
+            return Ok(None);
+        }
+
+        let idle = now.duration_since(self.last_seen);
+        if !self.active {
+            if idle >= interval + self.config.timeout {
+                return Err(anyhow!("HiveCore did not answer during the handshake"));
+            }
+            return Ok(None);
+        }
+
+        if idle < interval {
+            return Ok(None);
+        }
+        self.seq += 1;
+        debug!("Sending PING {} to HiveCore", self.seq);
+        self.outstanding = Some((self.seq, now));
+        Ok(Some(format!("PING {} HIVE\r\n", self.seq)))
+    }
+}
+
+/// Result of waiting for the next frame from HiveCore.
+pub enum Inbound {
+    Frame(Vec<u8>),
+    /// The connection has been idle; this `PING` line should be sent.
+    PingDue(String),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Heartbeat, HeartbeatConfig};
+    use std::time::{Duration, Instant};
+
+    fn config() -> HeartbeatConfig {
+        HeartbeatConfig {
+            interval: Some(Duration::from_secs(30)),
+            timeout: Duration::from_secs(10),
+            write_timeout: None,
+            keepalive_idle: None,
+            keepalive_interval: Duration::from_secs(10),
+            keepalive_retries: 3,
+        }
+    }
+
+    #[test]
+    fn pings_after_interval_and_fails_after_timeout() {
+        let mut heartbeat = Heartbeat::new(config());
+        heartbeat.start();
+        let start = heartbeat.last_seen;
+
+        assert_eq!(
+            heartbeat
+                .check_idle(start + Duration::from_secs(5))
+                .unwrap(),
+            None
+        );
+        assert_eq!(
+            heartbeat
+                .check_idle(start + Duration::from_secs(31))
+                .unwrap()
+                .as_deref(),
+            Some("PING 1 HIVE\r\n")
+        );
+        assert!(heartbeat
+            .check_idle(start + Duration::from_secs(42))
+            .is_err());
+    }
+
+    #[test]
+    fn activity_clears_outstanding_ping() {
+        let mut heartbeat = Heartbeat::new(config());
+        heartbeat.start();
+        heartbeat
+            .check_idle(Instant::now() + Duration::from_secs(31))
+            .unwrap();
+
+        heartbeat.on_activity();
+        assert!(heartbeat.outstanding.is_none());
+        assert!(heartbeat
+            .check_idle(Instant::now() + Duration::from_secs(5))
+            .is_ok());
+    }
+}
@@ -0,0 +1,213 @@
+use anyhow::Result;
+use log::{info, warn};
+use std::fmt::{Display, Formatter};
+use std::time::Duration;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio::time::Instant;
+
+use crate::messages::proxy_message::ProxyMessage;
+
+/// Feature HiveCore lists on `AUTH-OK` when it wants jobs acknowledged.
+pub const LEASE_FEATURE: &str = "job-lease";
+
+/// Header HiveCore uses to name a job it wants acknowledged.
+pub const JOB_ID_HEADER: &str = "X-Hive-Job-Id";
+/// How long HiveCore holds the job for this node before reassigning it.
+pub const LEASE_HEADER: &str = "X-Hive-Lease-Ms";
+
+/// Why a leased job was handed back to HiveCore without a response.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum NackReason {
+    /// The backend could not be reached.
+    BackendDown,
+    /// The backend does not have the requested model.
+    ModelMissing,
+    /// The backend ran out of memory loading or running the model.
+    OutOfMemory,
+    /// The backend failed with another server error.
+    BackendError,
+    /// The model is outside this cluster's allowlist.
+    ModelNotServed,
+    /// The lease ran out before the node could start the job.
+    LeaseExpired,
+}
+
+impl NackReason {
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::BackendDown => "BACKEND_DOWN",
+            Self::ModelMissing => "MODEL_MISSING",
+            Self::OutOfMemory => "OOM",
+            Self::BackendError => "BACKEND_ERROR",
+            Self::ModelNotServed => "MODEL_NOT_SERVED",
+            Self::LeaseExpired => "LEASE_EXPIRED",
+        }
+    }
+
+    /// Whether a reply with `status` may be handed back, which
+    /// `for_backend_status` decides from its body.
+    pub fn may_hand_back(status: u16) -> bool {
+        status == 404 || (500..=599).contains(&status)
+    }
+
+    /// Maps a backend reply to a reason, or `None` when the reply should be
+    /// relayed to the client as is. A 404 is only handed back when the model
+    /// is missing, not for an unknown path.
+    pub fn for_backend_status(status: u16, body: &str) -> Option<Self> {
+        match status {
+            404 => {
+                let body = body.to_ascii_lowercase();
+                let missing = body.contains("not found") || body.contains("does not exist");
+                (body.contains("model") && missing).then_some(Self::ModelMissing)
+            }
+            500..=599 => {
+                let body = body.to_ascii_lowercase();
+                if body.contains("out of memory") || body.contains("requires more system memory") {
+                    Some(Self::OutOfMemory)
+                } else {
+                    Some(Self::BackendError)
+                }
+            }
+            _ => None,
+        }
+    }
+}
+
+impl Display for NackReason {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.code())
+    }
+}
+
+/// A proxied request HiveCore asked the node to acknowledge. The node answers
+/// with `ACCEPT` before starting it and `DONE` after the response, or hands
+/// it back with `NACK` so HiveCore can run it elsewhere.
+#[derive(Debug)]
+pub struct JobLease {
+    pub job_id: String,
+    deadline: Option<Instant>,
+}
+
+impl JobLease {
+    /// Removes the lease headers, which are meant for the node and not the
+    /// backend, and reads them when HiveCore negotiated leases for this
+    /// session. Requests without a job ID are not leased.
+    pub fn take(request: &mut ProxyMessage, negotiated: bool) -> Option<Self> {
+        let job_id = request
+            .header(JOB_ID_HEADER)
+            .map(|job_id| job_id.trim().to_string());
+        let deadline = request
+            .header(LEASE_HEADER)
+            .and_then(|lease| lease.trim().parse::<u64>().ok())
+            .map(|millis| Instant::now() + Duration::from_millis(millis));
+        request.headers.remove(JOB_ID_HEADER);
+        request.headers.remove(LEASE_HEADER);
+
+        let job_id = job_id.filter(|job_id| negotiated && !job_id.is_empty())?;
+        Some(Self { job_id, deadline })
+    }
+
+    /// Time left to start the job, `None` for an open-ended lease.
+    pub fn remaining(&self) -> Option<Duration> {
+        self.deadline
+            .map(|deadline| deadline.saturating_duration_since(Instant::now()))
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.remaining() == Some(Duration::ZERO)
+    }
+
+    pub async fn accept<W: AsyncWrite + Unpin>(&self, out: &mut W) -> Result<()> {
+        write_line(out, &format!("ACCEPT {} HIVE\r\n", self.job_id)).await
+    }
+
+    pub async fn done<W: AsyncWrite + Unpin>(&self, out: &mut W) -> Result<()> {
+        info!("Job {} done", self.job_id);
+        write_line(out, &format!("DONE {} HIVE\r\n", self.job_id)).await
+    }
+
+    /// Hands the job back. Only valid while no response bytes were written.
+    pub async fn nack<W: AsyncWrite + Unpin>(
+        &self,
+        out: &mut W,
+        reason: NackReason,
+        detail: &str,
+    ) -> Result<()> {
+        warn!(
+            "Returning job {} to HiveCore: {} {}",
+            self.job_id, reason, detail
+        );
+        let detail = detail.trim();
+        let message = format!(
+            "NACK {} HIVE\r\nReason: {}\r\nContent-Length: {}\r\n\r\n{}",
+            self.job_id,
+            reason,
+            detail.len(),
+            detail
+        );
+        write_line(out, &message).await
+    }
+}
+
+async fn write_line<W: AsyncWrite + Unpin>(out: &mut W, line: &str) -> Result<()> {
+    out.write_all(line.as_bytes()).await?;
+    out.flush().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{JobLease, NackReason, JOB_ID_HEADER, LEASE_HEADER};
+    use crate::messages::proxy_message::ProxyMessage;
+
+    #[test]
+    fn reads_job_lease_headers() {
+        let leased = || {
+            ProxyMessage::parse(
+                b"POST /api/generate HTTP/1.1\r\nx-hive-job-id: job-7\r\nX-Hive-Lease-Ms: 0\r\n\r\n{}",
+            )
+            .unwrap()
+        };
+        let mut request = leased();
+        let lease = JobLease::take(&mut request, true).unwrap();
+        assert_eq!(lease.job_id, "job-7");
+        assert!(lease.is_expired());
+        assert!(!request.headers.contains(JOB_ID_HEADER));
+        assert!(!request.headers.contains(LEASE_HEADER));
+
+        // Without the negotiated feature the headers are dropped unread.
+        let mut request = leased();
+        assert!(JobLease::take(&mut request, false).is_none());
+        assert!(!request.headers.contains(JOB_ID_HEADER));
+
+        let mut unleased = ProxyMessage::parse(b"GET /api/tags HTTP/1.1\r\n\r\n").unwrap();
+        assert!(JobLease::take(&mut unleased, true).is_none());
+    }
+
+    #[test]
+    fn classifies_backend_failures() {
+        assert_eq!(
+            NackReason::for_backend_status(404, "model 'x' not found"),
+            Some(NackReason::ModelMissing)
+        );
+        assert_eq!(
+            NackReason::for_backend_status(404, "The model `x` does not exist."),
+            Some(NackReason::ModelMissing)
+        );
+        assert!(NackReason::may_hand_back(404));
+        assert_eq!(
+            NackReason::for_backend_status(404, "404 page not found"),
+            None
+        );
+        assert_eq!(NackReason::for_backend_status(404, ""), None);
+        assert_eq!(
+            NackReason::for_backend_status(500, "CUDA error: out of memory"),
+            Some(NackReason::OutOfMemory)
+        );
+        assert_eq!(
+            NackReason::for_backend_status(503, "busy"),
+            Some(NackReason::BackendError)
+        );
+        assert_eq!(NackReason::for_backend_status(400, "bad json"), None);
+    }
+}
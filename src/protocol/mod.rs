@@ -1,5 +1,23 @@
+pub mod auth;
 pub mod backend;
+pub mod cancel;
+pub mod cluster;
+pub mod commands;
 pub mod connection;
+pub mod control_auth;
 pub mod docker;
+pub mod drain;
+pub mod endpoints;
+pub mod env_util;
+pub mod events;
+pub mod frame;
+pub mod heartbeat;
+pub mod lease;
+pub mod model_admin;
+pub mod mux;
 pub mod network_util;
+pub mod reconnect;
+pub mod self_update;
 pub mod state;
+pub mod streaming;
+pub mod transport;
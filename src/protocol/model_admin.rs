@@ -0,0 +1,107 @@
+use crate::messages::proxy_message::{Headers, ProxyMessage};
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+/// Arguments of `PULL_MODEL` and `DELETE_MODEL`, sent as the `args` of the
+/// control message.
+#[derive(Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct ModelArgs {
+    pub model: String,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub insecure: bool,
+}
+
+#[derive(Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct CopyArgs {
+    pub source: String,
+    pub destination: String,
+}
+
+/// A model management command from HiveCore.
+#[derive(Debug, Eq, PartialEq)]
+pub enum ModelCommand {
+    Pull(ModelArgs),
+    Delete(ModelArgs),
+    Copy(CopyArgs),
+}
+
+impl ModelCommand {
+    pub fn parse(command: &str, args: &Value) -> Result<Self> {
+        let invalid = || format!("{command} has missing or invalid arguments");
+        match command {
+            "PULL_MODEL" => Ok(Self::Pull(
+                Deserialize::deserialize(args).with_context(invalid)?,
+            )),
+            "DELETE_MODEL" => Ok(Self::Delete(
+                Deserialize::deserialize(args).with_context(invalid)?,
+            )),
+            "COPY_MODEL" => Ok(Self::Copy(
+                Deserialize::deserialize(args).with_context(invalid)?,
+            )),
+            other => Err(anyhow!("{other} is not a model command")),
+        }
+    }
+
+    /// The Ollama API request that carries out the command. Pulls stream their
+    /// progress as NDJSON.
+    pub fn backend_request(&self) -> Result<ProxyMessage> {
+        let (method, uri) = match self {
+            Self::Pull(_) => ("POST", "/api/pull"),
+            Self::Delete(_) => ("DELETE", "/api/delete"),
+            Self::Copy(_) => ("POST", "/api/copy"),
+        };
+        let mut request = ProxyMessage {
+            protocol: "HTTP/1.1".into(),
+            method: method.into(),
+            uri: uri.into(),
+            headers: Headers::default(),
+            body: Vec::new(),
+        };
+        match self {
+            Self::Pull(args) | Self::Delete(args) => request.set_json(args)?,
+            Self::Copy(args) => request.set_json(args)?,
+        }
+        Ok(request)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+
+    use super::{CopyArgs, ModelArgs, ModelCommand};
+
+    #[test]
+    fn parses_model_commands() {
+        assert_eq!(
+            ModelCommand::parse("PULL_MODEL", &json!({"model": "llama3.2"})).unwrap(),
+            ModelCommand::Pull(ModelArgs {
+                model: "llama3.2".into(),
+                insecure: false,
+            })
+        );
+        assert_eq!(
+            ModelCommand::parse("COPY_MODEL", &json!({"source": "a", "destination": "b"})).unwrap(),
+            ModelCommand::Copy(CopyArgs {
+                source: "a".into(),
+                destination: "b".into(),
+            })
+        );
+        assert!(ModelCommand::parse("DELETE_MODEL", &Value::Null).is_err());
+    }
+
+    #[test]
+    fn builds_ollama_requests() {
+        let pull = ModelCommand::parse("PULL_MODEL", &json!({"model": "qwen3:8b"}))
+            .unwrap()
+            .backend_request()
+            .unwrap();
+        assert_eq!(
+            (pull.method.as_str(), pull.uri.as_str()),
+            ("POST", "/api/pull")
+        );
+        assert_eq!(pull.body, b"{\"model\":\"qwen3:8b\"}");
+        assert!(pull.modifies_poll());
+    }
+}
@@ -0,0 +1,624 @@
+use anyhow::{anyhow, Context, Result};
+use log::{debug, info, warn};
+use reqwest::Client;
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::io::{duplex, split, AsyncReadExt, AsyncWriteExt, WriteHalf};
+use tokio::sync::{mpsc, watch};
+use tokio::task::{AbortHandle, JoinSet};
+
+use crate::messages::proxy_message::{ParseError, ProxyMessage};
+
+use super::cluster::ClusterConfig;
+use super::docker::DOCKER_UPGRADE_LOCK;
+use super::drain::DRAINING_MESSAGE;
+use super::endpoints::Failback;
+use super::env_util::env_bool;
+use super::frame::{protocol_error, FrameError, FrameReader};
+use super::heartbeat::{Heartbeat, Inbound};
+use super::network_util::{handle_control_request, serve_request, ServeOptions, PAUSED_MESSAGE};
+use super::state::{
+    get_shutdown, is_paused, notify_refresh, paused_becomes, session_end_requested, PollState,
+};
+use super::streaming::StreamConfig;
+use super::transport::CoreStream;
+
+/// Feature name negotiated through `AUTH-OK` and `NODE-INFO`.
+pub const MUX_FEATURE: &str = "mux";
+
+/// Largest `DATA` payload the node sends.
+const MUX_CHUNK_BYTES: usize = 16 * 1024;
+/// Outbound `DATA` frames queued before jobs wait for the socket.
+const DATA_QUEUE_FRAMES: usize = 64;
+
+/// Whether this node offers multiplexing; `HIVE_MUX=false` keeps one job per
+/// connection even against a HiveCore that supports it.
+pub fn mux_enabled() -> bool {
+    env_bool("HIVE_MUX", true)
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum FrameKind {
+    /// HiveCore -> node: a job or worker command, opening a stream.
+    Request = 1,
+    /// Node -> HiveCore: response bytes for a stream.
+    Data = 2,
+    /// Node -> HiveCore: the stream's response is complete.
+    End = 3,
+    /// Either direction: abandon the stream. The payload is a reason.
+    Reset = 4,
+    /// Either direction on stream 0: connection-level `HIVE` lines.
+    Control = 5,
+}
+
+impl FrameKind {
+    fn from_byte(byte: u8) -> Option<Self> {
+        match byte {
+            1 => Some(Self::Request),
+            2 => Some(Self::Data),
+            3 => Some(Self::End),
+            4 => Some(Self::Reset),
+            5 => Some(Self::Control),
+            _ => None,
+        }
+    }
+}
+
+/// One frame of a multiplexed session: `len | stream_id | kind | payload`,
+/// where the 4-byte big-endian `len` counts everything after itself.
+#[derive(Debug, Eq, PartialEq)]
+pub struct MuxFrame {
+    pub stream_id: u32,
+    pub kind: FrameKind,
+    pub payload: Vec<u8>,
+}
+
+impl MuxFrame {
+    pub fn new(stream_id: u32, kind: FrameKind, payload: Vec<u8>) -> Self {
+        Self {
+            stream_id,
+            kind,
+            payload,
+        }
+    }
+
+    pub fn control(line: String) -> Self {
+        Self::new(0, FrameKind::Control, line.into_bytes())
+    }
+
+    /// Decodes a frame body as returned by `FrameReader`, i.e. without the
+    /// length prefix.
+    pub fn decode(body: Vec<u8>) -> Result<Self> {
+        if body.len() < 5 {
+            return Err(anyhow!(
+                "Multiplexed frame of {} bytes is too short",
+                body.len()
+            ));
+        }
+        let stream_id = Self::stream_id_of(&body).unwrap_or_default();
+        let kind = FrameKind::from_byte(body[4])
+            .ok_or_else(|| anyhow!("Unknown multiplexed frame kind {}", body[4]))?;
+        Ok(Self::new(stream_id, kind, body[5..].to_vec()))
+    }
+
+    /// The stream id of a frame body, if it is long enough to carry one.
+    fn stream_id_of(body: &[u8]) -> Option<u32> {
+        body.get(..4)
+            .map(|id| u32::from_be_bytes([id[0], id[1], id[2], id[3]]))
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let len = (self.payload.len() + 5) as u32;
+        let mut bytes = Vec::with_capacity(self.payload.len() + 9);
+        bytes.extend_from_slice(&len.to_be_bytes());
+        bytes.extend_from_slice(&self.stream_id.to_be_bytes());
+        bytes.push(self.kind as u8);
+        bytes.extend_from_slice(&self.payload);
+        bytes
+    }
+
+    fn message(&self) -> Result<ProxyMessage, ParseError> {
+        ProxyMessage::parse(&self.payload)
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SessionMode {
+    /// The leading connection has not authenticated yet.
+    Negotiating,
+    /// One multiplexed connection carries all of the cluster's jobs.
+    Multiplexed,
+    /// Every slot runs its own one-job-per-connection session.
+    PerConnection,
+    /// HiveCore refused the node for good; no slot connects again.
+    Stopped,
+}
+
+/// Shares the negotiated session mode between the connection slots of one
+/// cluster. Slot 0 negotiates; the other slots only connect while the cluster
+/// runs one job per connection.
+pub struct ClusterSessions {
+    offer_mux: bool,
+    mode: watch::Sender<SessionMode>,
+}
+
+impl ClusterSessions {
+    pub fn new(offer_mux: bool) -> Self {
+        let mode = if offer_mux {
+            SessionMode::Negotiating
+        } else {
+            SessionMode::PerConnection
+        };
+        Self {
+            offer_mux,
+            mode: watch::Sender::new(mode),
+        }
+    }
+
+    pub fn offers_mux(&self, slot: usize) -> bool {
+        self.offer_mux && slot == 0
+    }
+
+    /// Publishes the negotiated mode, unless the cluster was stopped.
+    pub fn publish(&self, mode: SessionMode) {
+        self.mode.send_if_modified(|current| {
+            let changed = *current != SessionMode::Stopped && *current != mode;
+            if changed {
+                *current = mode;
+            }
+            changed
+        });
+    }
+
+    /// Stops every slot of the cluster, e.g. after a permanent `AUTH-FAIL`.
+    /// Sessions that are running end on their own.
+    pub fn stop(&self) {
+        self.mode.send_replace(SessionMode::Stopped);
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        *self.mode.borrow() == SessionMode::Stopped
+    }
+
+    /// Waits until the cluster runs one job per connection, or is stopped.
+    pub async fn wait_for_per_connection(&self) {
+        let mut mode = self.mode.subscribe();
+        // The sender lives as long as `self`, so this cannot fail.
+        let _ = mode
+            .wait_for(|mode| matches!(mode, SessionMode::PerConnection | SessionMode::Stopped))
+            .await;
+    }
+}
+
+/// Runs a multiplexed session until HiveCore goes away or the session is
+/// asked to end. Up to the cluster's concurrency jobs run at once, each on
+/// its own stream; every outstanding `POLL` is one credit for a new job.
+#[allow(clippy::too_many_arguments)]
+pub async fn run_mux_session(
+    stream: CoreStream,
+    mut reader: FrameReader,
+    mut heartbeat: Heartbeat,
+    mut failback: Failback,
+    mut polls: PollState,
+    cluster: &Arc<ClusterConfig>,
+    client: &Client,
+    options: ServeOptions,
+) -> Result<()> {
+    let (mut read_half, write_half) = split(stream);
+    let (control_tx, control_rx) = mpsc::unbounded_channel();
+    let (data_tx, data_rx) = mpsc::channel(DATA_QUEUE_FRAMES);
+    let mut writer = tokio::spawn(write_frames(write_half, control_rx, data_rx));
+
+    let mut capacity = cluster.concurrency;
+    let mut outstanding_polls = 0usize;
+    let mut jobs: JoinSet<(u32, Result<bool>)> = JoinSet::new();
+    let mut streams: HashMap<u32, AbortHandle> = HashMap::new();
+    let mut commands: JoinSet<()> = JoinSet::new();
+    let mut ending = false;
+    let mut paused = is_paused();
+    info!(
+        "Multiplexing up to {} jobs over one connection to {}",
+        capacity, cluster.name
+    );
+
+    loop {
+        if ending && jobs.is_empty() && commands.is_empty() {
+            // Let the writer flush the last END frames before disconnecting.
+            drop(control_tx);
+            drop(data_tx);
+            return writer.await?;
+        }
+
+        if !ending {
+            if polls.is_stale() {
+                let _read_guard = DOCKER_UPGRADE_LOCK.read().await;
+                polls
+                    .refresh(client, cluster)
+                    .await
+                    .context("Error refreshing models")?;
+            }
+            while !paused && outstanding_polls + jobs.len() < capacity {
+                control_tx.send(MuxFrame::control(polls.next_poll()?))?;
+                outstanding_polls += 1;
+            }
+        }
+
+        tokio::select! {
+            inbound = heartbeat.next_frame(&mut read_half, &mut reader) => {
+                let frame = match inbound {
+                    Ok(Inbound::Frame(body)) => {
+                        let stream_id = MuxFrame::stream_id_of(&body);
+                        match MuxFrame::decode(body) {
+                            Ok(frame) => frame,
+                            Err(e) => {
+                                // Only this frame is lost; the next one
+                                // still starts after its length prefix.
+                                warn!("Refusing a malformed frame from HiveCore: {}", e);
+                                control_tx.send(refuse_malformed(stream_id, &e))?;
+                                continue;
+                            }
+                        }
+                    }
+                    Ok(Inbound::PingDue(ping)) => {
+                        control_tx.send(MuxFrame::control(ping))?;
+                        continue;
+                    }
+                    Err(e) => match e.downcast_ref::<FrameError>() {
+                        Some(FrameError::TooLarge { head, .. }) => {
+                            match MuxFrame::decode(head.clone()) {
+                                Ok(frame) if frame.kind == FrameKind::Request => {
+                                    outstanding_polls = outstanding_polls.saturating_sub(1);
+                                    control_tx.send(MuxFrame::new(
+                                        frame.stream_id,
+                                        FrameKind::Reset,
+                                        e.to_string().into_bytes(),
+                                    ))?;
+                                }
+                                _ => warn!("Ignoring a frame from HiveCore: {}", e),
+                            }
+                            continue;
+                        }
+                        Some(refused) => {
+                            // Nothing more can be read, so running jobs end
+                            // with the connection.
+                            control_tx.send(MuxFrame::control(refused.message()))?;
+                            jobs.shutdown().await;
+                            commands.shutdown().await;
+                            drop(control_tx);
+                            drop(data_tx);
+                            let _ = writer.await;
+                            return Err(e);
+                        }
+                        None => return Err(e),
+                    },
+                };
+                match frame.kind {
+                    FrameKind::Request => {
+                        let request = match frame.message() {
+                            Ok(request) => request,
+                            Err(e) => {
+                                // Most likely a job, so its poll is spent.
+                                warn!("Resetting malformed request on stream {}: {}", frame.stream_id, e);
+                                outstanding_polls = outstanding_polls.saturating_sub(1);
+                                control_tx.send(MuxFrame::new(
+                                    frame.stream_id,
+                                    FrameKind::Reset,
+                                    e.to_string().into_bytes(),
+                                ))?;
+                                continue;
+                            }
+                        };
+                        if request.protocol != "HIVE" {
+                            outstanding_polls = outstanding_polls.saturating_sub(1);
+                        }
+                        if streams.contains_key(&frame.stream_id) {
+                            // The running job keeps the stream, and stays
+                            // reachable by a later RESET.
+                            warn!("Resetting a request on stream {}, which is still in use", frame.stream_id);
+                            control_tx.send(MuxFrame::new(
+                                frame.stream_id,
+                                FrameKind::Reset,
+                                b"Stream is already in use".to_vec(),
+                            ))?;
+                            continue;
+                        }
+                        let job = jobs.spawn(run_job(
+                            frame.stream_id,
+                            request,
+                            cluster.clone(),
+                            client.clone(),
+                            data_tx.clone(),
+                            options.clone(),
+                        ));
+                        streams.insert(frame.stream_id, job);
+                    }
+                    FrameKind::Reset => {
+                        if let Some(job) = streams.remove(&frame.stream_id) {
+                            warn!(
+                                "HiveCore reset stream {}: {}",
+                                frame.stream_id,
+                                String::from_utf8_lossy(&frame.payload)
+                            );
+                            job.abort();
+                        }
+                    }
+                    FrameKind::Control => {
+                        let Ok(message) = frame.message() else {
+                            warn!("Ignoring malformed control frame from HiveCore");
+                            continue;
+                        };
+                        if heartbeat.is_own_pong(&message) {
+                            continue;
+                        }
+                        if message.method == "CAPACITY" {
+                            let Ok(requested) = message.uri.parse::<usize>() else {
+                                debug!("Ignoring control message CAPACITY {}", message.uri);
+                                continue;
+                            };
+                            capacity = requested.min(cluster.concurrency);
+                            info!("HiveCore set the capacity of {} to {}", cluster.name, capacity);
+                            let withdrawn = polls_to_withdraw(outstanding_polls, jobs.len(), capacity);
+                            if withdrawn > 0 {
+                                control_tx.send(MuxFrame::control(format!("WITHDRAW {withdrawn} HIVE\r\n")))?;
+                                outstanding_polls -= withdrawn;
+                            }
+                            continue;
+                        }
+                        let (cluster, client, control_tx) = (cluster.clone(), client.clone(), control_tx.clone());
+                        let config = options.stream.clone();
+                        commands.spawn(async move {
+                            match answer_control(&message, &cluster, &client, &config).await {
+                                Ok(Some(reply)) => {
+                                    let _ = control_tx.send(reply);
+                                }
+                                Ok(None) => {}
+                                Err(e) => warn!("Control command {} failed: {:#}", message.method, e),
+                            }
+                        });
+                    }
+                    kind => warn!("Ignoring unexpected {:?} frame from HiveCore", kind),
+                }
+            }
+            Some(_) = commands.join_next(), if !commands.is_empty() => {}
+            Some(joined) = jobs.join_next(), if !jobs.is_empty() => {
+                match joined {
+                    Ok((stream_id, result)) => {
+                        streams.remove(&stream_id);
+                        if let Ok(true) = result {
+                            notify_refresh();
+                        }
+                    }
+                    Err(e) if e.is_cancelled() => {}
+                    Err(e) => warn!("Multiplexed job failed: {}", e),
+                }
+            }
+            preferred = failback.preferred_available(), if !ending => {
+                info!(
+                    "Leaving standby HiveCore to reconnect to {} once {} jobs finish",
+                    preferred.addr,
+                    jobs.len()
+                );
+                ending = true;
+            }
+            _ = paused_becomes(!paused), if !ending => {
+                paused = !paused;
+                if paused {
+                    // Withdraws every outstanding poll of this connection.
+                    control_tx.send(MuxFrame::control(PAUSED_MESSAGE.to_string()))?;
+                    outstanding_polls = 0;
+                }
+            }
+            _ = session_end_requested(), if !ending => {
+                if get_shutdown() {
+                    control_tx.send(MuxFrame::control(DRAINING_MESSAGE.to_string()))?;
+                }
+                ending = true;
+            }
+            written = &mut writer => {
+                return Err(match written {
+                    Ok(Err(e)) => e,
+                    Ok(Ok(())) => anyhow!("HiveCore writer stopped"),
+                    Err(e) => e.into(),
+                });
+            }
+        }
+    }
+}
+
+/// Answers a frame that does not decode: its stream is reset when the frame
+/// names one, otherwise HiveCore gets a `PROTOCOL-ERROR` control frame.
+fn refuse_malformed(stream_id: Option<u32>, error: &anyhow::Error) -> MuxFrame {
+    match stream_id {
+        Some(stream_id) if stream_id != 0 => {
+            MuxFrame::new(stream_id, FrameKind::Reset, error.to_string().into_bytes())
+        }
+        _ => MuxFrame::control(protocol_error("malformed_frame", &error.to_string())),
+    }
+}
+
+/// Polls to withdraw when the capacity drops to `capacity`: those that,
+/// with the running jobs, are above it.
+fn polls_to_withdraw(outstanding_polls: usize, running: usize, capacity: usize) -> usize {
+    (outstanding_polls + running)
+        .saturating_sub(capacity)
+        .min(outstanding_polls)
+}
+
+/// Runs a command from the control stream like one on a one-job-per-connection
+/// session. Its reply, if it has one, goes back as a single `CONTROL` frame.
+async fn answer_control(
+    message: &ProxyMessage,
+    cluster: &ClusterConfig,
+    client: &Client,
+    config: &StreamConfig,
+) -> Result<Option<MuxFrame>> {
+    let mut reply = Vec::new();
+    if handle_control_request(message, cluster, &mut reply, client, config).await? {
+        notify_refresh();
+    }
+    Ok((!reply.is_empty()).then(|| MuxFrame::new(0, FrameKind::Control, reply)))
+}
+
+/// Serves one stream: the reply is written into an in-memory pipe and pumped
+/// out as `DATA` frames, followed by `END` or, on failure, `RESET`.
+async fn run_job(
+    stream_id: u32,
+    request: ProxyMessage,
+    cluster: Arc<ClusterConfig>,
+    client: Client,
+    data: mpsc::Sender<MuxFrame>,
+    options: ServeOptions,
+) -> (u32, Result<bool>) {
+    let (job_io, pump_io) = duplex(MUX_CHUNK_BYTES);
+
+    let serve = async {
+        let mut job_io = job_io;
+        let result = serve_request(request, &cluster, &mut job_io, &client, &options).await;
+        let _ = job_io.shutdown().await;
+        result
+    };
+    let pump = async {
+        // Owned here so a failed pump drops its end and unblocks `serve`.
+        let mut pump_io = pump_io;
+        let mut buffer = vec![0u8; MUX_CHUNK_BYTES];
+        loop {
+            let n = pump_io.read(&mut buffer).await?;
+            if n == 0 {
+                return Ok(());
+            }
+            let frame = MuxFrame::new(stream_id, FrameKind::Data, buffer[..n].to_vec());
+            data.send(frame)
+                .await
+                .map_err(|_| anyhow!("HiveCore connection closed"))?;
+        }
+    };
+    let (result, pumped): (Result<bool>, Result<()>) = tokio::join!(serve, pump);
+
+    if pumped.is_ok() {
+        let last = match &result {
+            Ok(_) => MuxFrame::new(stream_id, FrameKind::End, vec![]),
+            Err(e) => MuxFrame::new(stream_id, FrameKind::Reset, format!("{e:#}").into_bytes()),
+        };
+        let _ = data.send(last).await;
+    }
+    (stream_id, result)
+}
+
+/// Writes queued frames to HiveCore. Control frames (polls, pings) go first
+/// so they are not stuck behind response data.
+async fn write_frames(
+    mut out: WriteHalf<CoreStream>,
+    mut control: mpsc::UnboundedReceiver<MuxFrame>,
+    mut data: mpsc::Receiver<MuxFrame>,
+) -> Result<()> {
+    loop {
+        let frame = tokio::select! {
+            biased;
+            Some(frame) = control.recv() => frame,
+            Some(frame) = data.recv() => frame,
+            else => return Ok(()),
+        };
+        out.write_all(&frame.encode()).await?;
+        out.flush().await?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        answer_control, polls_to_withdraw, refuse_malformed, ClusterSessions, FrameKind, MuxFrame,
+        SessionMode,
+    };
+    use crate::messages::proxy_message::ProxyMessage;
+    use crate::protocol::cluster::ClusterConfig;
+    use crate::protocol::streaming::StreamConfig;
+    use std::time::Duration;
+
+    #[test]
+    fn frames_round_trip() {
+        let frame = MuxFrame::new(7, FrameKind::Data, b"hello".to_vec());
+        let bytes = frame.encode();
+        assert_eq!(&bytes[..4], &10u32.to_be_bytes());
+        assert_eq!(MuxFrame::decode(bytes[4..].to_vec()).unwrap(), frame);
+    }
+
+    #[test]
+    fn rejects_unknown_kinds_and_short_frames() {
+        assert!(MuxFrame::decode(vec![0, 0, 0, 1, 9]).is_err());
+        assert!(MuxFrame::decode(vec![0, 0, 1]).is_err());
+    }
+
+    #[test]
+    fn refuses_malformed_frames_without_ending_the_session() {
+        let body = vec![0, 0, 0, 7, 9, b'x'];
+        let error = MuxFrame::decode(body.clone()).unwrap_err();
+        let reply = refuse_malformed(MuxFrame::stream_id_of(&body), &error);
+        assert_eq!((reply.stream_id, reply.kind), (7, FrameKind::Reset));
+
+        let body = vec![0, 0, 1];
+        let error = MuxFrame::decode(body.clone()).unwrap_err();
+        let reply = refuse_malformed(MuxFrame::stream_id_of(&body), &error);
+        assert_eq!((reply.stream_id, reply.kind), (0, FrameKind::Control));
+        let reply = String::from_utf8(reply.payload).unwrap();
+        assert!(
+            reply.starts_with("PROTOCOL-ERROR malformed_frame HIVE\r\n"),
+            "{reply}"
+        );
+    }
+
+    #[tokio::test]
+    async fn stopped_clusters_stay_stopped() {
+        let sessions = ClusterSessions::new(true);
+        sessions.stop();
+        sessions.publish(SessionMode::Multiplexed);
+        assert!(sessions.is_stopped());
+        tokio::time::timeout(Duration::from_secs(1), sessions.wait_for_per_connection())
+            .await
+            .unwrap();
+    }
+
+    #[test]
+    fn withdraws_polls_above_a_lower_capacity() {
+        assert_eq!(polls_to_withdraw(3, 1, 4), 0);
+        assert_eq!(polls_to_withdraw(3, 1, 2), 2);
+        // Running jobs are not interrupted, so every poll goes.
+        assert_eq!(polls_to_withdraw(1, 3, 2), 1);
+        assert_eq!(polls_to_withdraw(0, 3, 1), 0);
+    }
+
+    #[tokio::test]
+    async fn answers_commands_on_the_control_stream() {
+        let cluster = ClusterConfig {
+            name: "default".into(),
+            core_url: "core:7777".into(),
+            key: "worker-secret".into(),
+            concurrency: 2,
+            models: None,
+            core_public_key: None,
+        };
+        let client = reqwest::Client::new();
+        let config = StreamConfig {
+            flush_bytes: 16 * 1024,
+            flush_after: Duration::from_millis(5),
+        };
+
+        let pause = ProxyMessage::parse(
+            b"CONTROL / HIVE\r\n\r\n{\"command\":\"PAUSE\",\"request_id\":\"c-1\"}",
+        )
+        .unwrap();
+        let reply = answer_control(&pause, &cluster, &client, &config)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!((reply.stream_id, reply.kind), (0, FrameKind::Control));
+        let reply = String::from_utf8(reply.payload).unwrap();
+        assert!(reply.starts_with("HTTP/1.1 403 Forbidden\r\n"), "{reply}");
+        assert!(reply.contains("X-Hive-Request-Id: c-1\r\n"), "{reply}");
+
+        let pong = ProxyMessage::parse(b"PONG / HIVE\r\n\r\n").unwrap();
+        assert!(answer_control(&pong, &cluster, &client, &config)
+            .await
+            .unwrap()
+            .is_none());
+    }
+}
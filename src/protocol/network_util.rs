@@ -1,218 +1,608 @@
 use anyhow::{anyhow, Result};
 use influxdb2::models::DataPoint;
-use log::{error, info, warn};
-use reqwest::blocking::Client;
-use reqwest::blocking::Response;
-use std::env;
-use std::io::{BufRead, BufReader, Read, Write};
-use std::net::TcpStream;
-use std::thread;
-use tokio::runtime::Runtime;
+use log::{debug, error, info, warn};
+use reqwest::header::{HeaderMap, CONNECTION, CONTENT_LENGTH, TRANSFER_ENCODING};
+use reqwest::{Client, StatusCode};
+use tokio::io::{split, AsyncWrite, AsyncWriteExt};
+use tokio::time::timeout;
 
 use crate::logging::log_influx;
 use crate::messages::proxy_message::ProxyMessage;
-use crate::protocol::state::{notify_refresh, set_node_name};
+use crate::models::node_info::NodeInfo;
+use crate::protocol::state::{cluster_node_name, set_node_name};
+
+use super::auth::{
+    challenge_response, check_auth_failure, core_features, get_auth_mode, key_id,
+    parse_auth_result, AuthMode,
+};
+use super::backend::{backend_version, get_backend, make_backend_request};
+use super::cancel::{JobCancelled, RunningJob};
+use super::cluster::ClusterConfig;
+use super::commands::{
+    find_command, is_command_request, CommandReply, ControlRequest, REQUEST_ID_HEADER,
+    WORKER_COMMAND_PATH,
+};
+use super::control_auth::{audit_rejection, verify_control, ControlRejected};
+use super::docker::DOCKER_UPGRADE_LOCK;
+use super::frame::{FrameError, FrameReader};
+use super::heartbeat::{Heartbeat, Inbound};
+use super::lease::{JobLease, NackReason, JOB_ID_HEADER, LEASE_FEATURE};
+use super::mux::MUX_FEATURE;
+use super::state::PollState;
+use super::streaming::{
+    stream_body, write_read_body, write_to_both_streams, BackendBody, BodyFraming, StreamConfig,
+};
+use super::transport::CoreStream;
+
+/// Line sent to HiveCore when a connection stops polling because the node
+/// was paused.
+pub const PAUSED_MESSAGE: &str = "PAUSED / HIVE\r\n";
+
+/// How one session serves jobs: the startup streaming settings and what
+/// HiveCore negotiated for it.
+#[derive(Clone, Debug)]
+pub struct ServeOptions {
+    pub stream: StreamConfig,
+    /// Copied from `SessionFeatures::leases`.
+    pub leases: bool,
+}
 
-use super::backend::{backend_version, get_backend, make_backend_request, InferenceBackend};
-use super::docker::{is_docker_managed, upgrade_ollama_docker};
-use super::state::set_reboot;
-use super::state::set_shutdown;
+/// Optional features HiveCore agreed to on `AUTH-OK` for one session.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct SessionFeatures {
+    pub multiplexed: bool,
+    /// Jobs with an `X-Hive-Job-Id` are acknowledged, see `JobLease`.
+    pub leases: bool,
+}
 
-pub fn authenticate(stream: &mut TcpStream, nonce: u64, client: &Client) -> Result<()> {
-    let key = env::var("HIVE_KEY").expect("HIVE_KEY");
-    let backend_version = backend_version(client);
+pub async fn authenticate(
+    stream: &mut CoreStream,
+    reader: &mut FrameReader,
+    heartbeat: &mut Heartbeat,
+    cluster: &ClusterConfig,
+    nonce: u64,
+    client: &Client,
+    offer_mux: bool,
+) -> Result<SessionFeatures> {
+    let key = &cluster.key;
+    let backend_version = backend_version(client).await;
     let node_version: &str = env!("CARGO_PKG_VERSION");
 
-    // Create an HTTP client
-    let auth_request = format!("AUTH {key};{nonce};{node_version};{backend_version} HIVE\r\n");
-    stream.write_all(auth_request.as_bytes())?;
-    stream.flush()?;
+    let auth_mode = get_auth_mode()?;
+    let response = match auth_mode {
+        AuthMode::Legacy => {
+            let auth_request =
+                format!("AUTH {key};{nonce};{node_version};{backend_version} HIVE\r\n");
+            stream.write_all(auth_request.as_bytes()).await?;
+            stream.flush().await?;
+            read_next_message(stream, reader, heartbeat).await?
+        }
+        AuthMode::Challenge => {
+            let key_id = key_id(key);
+            let hello = format!(
+                "AUTH-CHALLENGE {key_id};{nonce};{node_version};{backend_version} HIVE\r\n"
+            );
+            stream.write_all(hello.as_bytes()).await?;
+            stream.flush().await?;
+
+            let challenge = read_next_message(stream, reader, heartbeat).await?;
+            check_auth_failure(&challenge)?;
+            if challenge.method != "CHALLENGE" || challenge.uri.is_empty() {
+                return Err(anyhow!(
+                    "Expected CHALLENGE from HiveCore, got `{} {}`",
+                    challenge.method,
+                    challenge.uri
+                ));
+            }
 
-    let response = read_next_message(stream)?;
-    info!("Authenticated as: {}", response.uri);
-    set_node_name(response.uri);
-    Ok(())
+            let proof = challenge_response(key, &challenge.uri, nonce);
+            stream
+                .write_all(format!("AUTH-RESPONSE {proof} HIVE\r\n").as_bytes())
+                .await?;
+            stream.flush().await?;
+            read_next_message(stream, reader, heartbeat).await?
+        }
+    };
+
+    let node_name = parse_auth_result(&response, auth_mode)?;
+    info!("Authenticated to {} as: {}", cluster.name, node_name);
+    set_node_name(&cluster.name, node_name);
+
+    // Both sides switch to multiplexed frames after NODE-INFO when HiveCore
+    // offered it on AUTH-OK and this connection advertises it.
+    let core_features = core_features(&response);
+    let features = SessionFeatures {
+        multiplexed: offer_mux && core_features.iter().any(|f| f == MUX_FEATURE),
+        leases: core_features.iter().any(|f| f == LEASE_FEATURE),
+    };
+    send_node_info(stream, backend_version, offer_mux).await?;
+    Ok(features)
 }
-pub fn poll(
-    stream: &mut TcpStream,
-    model_name: &String,
-    optimized_polling_sequence: &bool,
+
+/// Advertises the node's hardware, backend and supported features to HiveCore.
+async fn send_node_info(
+    stream: &mut CoreStream,
+    backend_version: String,
+    offer_mux: bool,
 ) -> Result<()> {
-    // polling with "-" will tell the HiveCore to take the last seen
-    // set of models as the possible tags. The Core will optimize the
-    // sequence in which the models are polled based on the previously
-    // handled work.
-    // if the last work was using model X, it will prioratize the model
-    // X work requests to handle. This minimizes the amount of switching
-    // of models in the worker VRAM.
-    // requres the worker to have previously sent the tags to the core,
-    // so that the core has the list to work with
-    // However, polling with X;Y;Z will set the sequence of models in
-    // which the work is polled
-    let poll_command = get_backend()?.poll_command();
-    let poll_target = if *optimized_polling_sequence {
-        format!("{poll_command} - HIVE\r\n")
-    } else {
-        format!("{poll_command} {model_name} HIVE\r\n")
-    };
+    let node_info = serde_json::to_string(&NodeInfo::collect(backend_version, offer_mux))?;
+    stream.write_all(b"NODE-INFO / HIVE\r\n").await?;
+    stream
+        .write_all(b"Content-Type: application/json\r\n")
+        .await?;
+    stream
+        .write_all(format!("Content-Length: {}\r\n\r\n", node_info.len()).as_bytes())
+        .await?;
+    stream.write_all(node_info.as_bytes()).await?;
+    stream.flush().await?;
+    Ok(())
+}
 
-    stream.write_all(poll_target.as_bytes())?;
-    stream.flush()?;
+pub async fn poll(stream: &mut CoreStream, polls: &mut PollState) -> Result<()> {
+    stream.write_all(polls.next_poll()?.as_bytes()).await?;
+    stream.flush().await?;
     Ok(())
 }
 
-pub fn handle_control_request(request: &ProxyMessage, stream: &mut TcpStream) -> Result<bool> {
+/// Runs a control command from HiveCore and writes its reply, echoing the
+/// command's request ID. Commands without a valid signature are refused and
+/// audited. Returns whether the local models may have changed.
+pub async fn handle_control_request<W: AsyncWrite + Unpin>(
+    request: &ProxyMessage,
+    cluster: &ClusterConfig,
+    stream: &mut W,
+    client: &Client,
+    config: &StreamConfig,
+) -> Result<bool> {
     if request.protocol == "HIVE" && request.method != "PONG" {
         info!("Recieved request from HiveCore: {:#?}", request);
     }
 
-    let command = match request.worker_command() {
-        Some(command) => command,
-        None => return Ok(false),
+    // Commands only run from HIVE frames. A client request proxied to
+    // `/worker/command` is refused whatever headers it carries.
+    if request.protocol != "HIVE" {
+        let rejected = ControlRejected::NotAFrame;
+        audit_rejection(request, cluster, WORKER_COMMAND_PATH, &rejected);
+        write_http_response(stream, "403 Forbidden", &format!("{rejected}.\n")).await?;
+        return Ok(false);
+    }
+
+    let parsed = ControlRequest::from_message(request);
+    if matches!(parsed, Ok(None)) {
+        return Ok(false);
+    }
+    let (command, request_id) = match &parsed {
+        Ok(Some(control)) => (control.command.as_str(), control.request_id.as_deref()),
+        _ => (request.method.as_str(), None),
+    };
+    if let Err(e) = verify_control(request, cluster) {
+        // A command that cannot be verified is refused like a rejected one,
+        // and the session goes on.
+        let reason = match e.downcast::<ControlRejected>() {
+            Ok(rejected) => {
+                audit_rejection(request, cluster, command, &rejected);
+                rejected.to_string()
+            }
+            Err(e) => {
+                error!("Cannot verify {}: {:#}", command, e);
+                format!("Cannot verify control command: {e:#}")
+            }
+        };
+        write_response(
+            stream,
+            "403 Forbidden",
+            TEXT_PLAIN,
+            &format!("{reason}.\n"),
+            request_id,
+        )
+        .await?;
+        return Ok(false);
+    }
+
+    let control = match parsed {
+        Ok(Some(control)) => control,
+        Ok(None) => {
+            write_http_response(stream, "400 Bad Request", "Unknown worker command.\n").await?;
+            return Ok(false);
+        }
+        Err(e) => {
+            warn!("Rejecting control message from HiveCore: {:#}", e);
+            write_response(
+                stream,
+                "400 Bad Request",
+                TEXT_PLAIN,
+                &format!("{e:#}\n"),
+                None,
+            )
+            .await?;
+            return Ok(false);
+        }
+    };
+    let request_id = control.request_id.as_deref();
+
+    let Some(spec) = find_command(&control.command) else {
+        warn!("Ignoring unknown HiveCore command: {}", control.command);
+        write_response(
+            stream,
+            "400 Bad Request",
+            TEXT_PLAIN,
+            "Unknown worker command.\n",
+            request_id,
+        )
+        .await?;
+        return Ok(false);
     };
 
-    match command {
-        "REBOOT" => {
-            set_reboot(true);
-            write_http_response(stream, "200 OK", "HiveNode will reconnect.\n")?;
+    match (spec.run)(&control, cluster, client).await? {
+        CommandReply::Text { status, body } => {
+            write_response(stream, status, TEXT_PLAIN, &body, request_id).await?
         }
-        "SHUTDOWN" => {
-            set_shutdown(true);
-            write_http_response(stream, "200 OK", "HiveNode is shutting down.\n")?;
+        CommandReply::Json { status, body } => {
+            write_response(stream, status, APPLICATION_JSON, &body, request_id).await?
         }
-        "UPDATE" | "UPDATE_OLLAMA" => handle_ollama_update(stream)?,
-        _ => {
-            warn!("Ignoring unknown HiveCore command: {}", command);
-            write_http_response(stream, "400 Bad Request", "Unknown worker command.\n")?;
+        CommandReply::Backend(backend_request) => {
+            let _read_guard = DOCKER_UPGRADE_LOCK.read().await;
+            return stream_response_to_proxy(
+                backend_request,
+                cluster,
+                stream,
+                client,
+                config,
+                None,
+                request_id,
+            )
+            .await;
         }
     }
 
     Ok(false)
 }
 
-fn handle_ollama_update(stream: &mut TcpStream) -> Result<()> {
-    if get_backend()? != InferenceBackend::Ollama {
-        warn!("Ignoring UPDATE_OLLAMA because HiveNode is using a vLLM backend.");
-        write_http_response(
-            stream,
-            "409 Conflict",
-            "UPDATE_OLLAMA is only available when INFERENCE_BACKEND=ollama.\n",
-        )?;
-        return Ok(());
-    }
+pub async fn write_http_response<W: AsyncWrite + Unpin>(
+    stream: &mut W,
+    status: &str,
+    body: &str,
+) -> Result<()> {
+    write_response(stream, status, TEXT_PLAIN, body, None).await
+}
 
-    if !is_docker_managed() {
-        warn!("Ignoring UPDATE_OLLAMA because HiveNode is using an external Ollama instance.");
-        write_http_response(
-            stream,
-            "409 Conflict",
-            "UPDATE_OLLAMA is only available when OLLAMA_MODE=docker.\n",
-        )?;
-        return Ok(());
+pub async fn write_json_response<W: AsyncWrite + Unpin>(
+    stream: &mut W,
+    status: &str,
+    body: &str,
+) -> Result<()> {
+    write_response(stream, status, APPLICATION_JSON, body, None).await
+}
+
+const TEXT_PLAIN: &str = "text/plain; charset=utf-8";
+const APPLICATION_JSON: &str = "application/json";
+
+async fn write_response<W: AsyncWrite + Unpin>(
+    stream: &mut W,
+    status: &str,
+    content_type: &str,
+    body: &str,
+    request_id: Option<&str>,
+) -> Result<()> {
+    let body_len = body.len();
+    stream
+        .write_all(format!("HTTP/1.1 {status}\r\n").as_bytes())
+        .await?;
+    stream
+        .write_all(format!("Content-Length: {body_len}\r\n").as_bytes())
+        .await?;
+    stream
+        .write_all(format!("Content-Type: {content_type}\r\n").as_bytes())
+        .await?;
+    if let Some(request_id) = request_id {
+        stream
+            .write_all(format!("{REQUEST_ID_HEADER}: {request_id}\r\n").as_bytes())
+            .await?;
     }
+    stream.write_all(b"Connection: close\r\n\r\n").await?;
+    stream.write_all(body.as_bytes()).await?;
+    stream.flush().await?;
+    Ok(())
+}
 
-    write_http_response(
-        stream,
-        "202 Accepted",
-        "Ollama Docker update started. HiveNode will reconnect when ready.\n",
-    )?;
-
-    thread::spawn(|| {
-        warn!("Attempting to upgrade Ollama Docker container...");
-        match Runtime::new() {
-            Ok(rt) => {
-                let upgrade_result = rt.block_on(async { upgrade_ollama_docker().await });
-                match upgrade_result {
-                    Ok(_) => {
-                        info!("Ollama Docker upgrade completed successfully.");
-                        notify_refresh();
-                        set_reboot(true);
-                    }
-                    Err(e) => {
-                        error!("Failed to upgrade Ollama Docker: {}", e);
-                    }
-                }
-            }
-            Err(e) => {
-                error!("Failed to create a Tokio runtime for Ollama upgrade: {}", e);
+/// Reads the next work or control message. While no data arrives the
+/// heartbeat keeps running, so a dead HiveCore is detected instead of waiting
+/// forever.
+pub async fn read_next_message(
+    stream: &mut CoreStream,
+    reader: &mut FrameReader,
+    heartbeat: &mut Heartbeat,
+) -> Result<ProxyMessage> {
+    loop {
+        let frame = match heartbeat.next_frame(stream, reader).await? {
+            Inbound::Frame(frame) => frame,
+            Inbound::PingDue(ping) => {
+                stream.write_all(ping.as_bytes()).await?;
+                stream.flush().await?;
+                continue;
             }
-        }
-        info!("Done updating Ollama Docker container...");
-    });
+        };
 
-    Ok(())
+        let message = ProxyMessage::parse(&frame)?;
+
+        // Answers to our own pings are consumed here; the caller only sees work
+        // and control messages.
+        if !heartbeat.is_own_pong(&message) {
+            return Ok(message);
+        }
+    }
 }
 
-fn write_http_response(stream: &mut TcpStream, status: &str, body: &str) -> Result<()> {
-    let body_len = body.len();
-    stream.write_all(format!("HTTP/1.1 {status}\r\n").as_bytes())?;
-    stream.write_all(format!("Content-Length: {body_len}\r\n").as_bytes())?;
-    stream.write_all(b"Content-Type: text/plain; charset=utf-8\r\n")?;
-    stream.write_all(b"Connection: close\r\n\r\n")?;
-    stream.write_all(body.as_bytes())?;
-    stream.flush()?;
+/// Tells HiveCore to withdraw this connection's outstanding polls because
+/// the node was paused.
+pub async fn announce_pause<W: AsyncWrite + Unpin>(stream: &mut W) -> Result<()> {
+    stream.write_all(PAUSED_MESSAGE.as_bytes()).await?;
+    stream.flush().await?;
     Ok(())
 }
 
-fn read_next_message_length(stream: &mut TcpStream) -> Result<usize> {
-    let mut len_buf = [0u8; 4];
-    if let Err(e) = stream.read_exact(&mut len_buf) {
-        error!("Error reading next message length from HiveCore: {}", e);
-        return Err(e.into());
+/// Handles one message from HiveCore and writes the reply to `out`: worker
+/// commands are verified and acknowledged, `/worker/command` requests are
+/// refused, requests for models outside the cluster's
+/// allowlist are refused and everything else is proxied to the backend.
+/// When the session negotiated leases, leased jobs that cannot run here
+/// are handed back with `NACK`. Returns whether the local models may have
+/// changed.
+pub async fn serve_request<W: AsyncWrite + Unpin>(
+    mut request: ProxyMessage,
+    cluster: &ClusterConfig,
+    out: &mut W,
+    client: &Client,
+    options: &ServeOptions,
+) -> Result<bool> {
+    if is_command_request(&request) {
+        return handle_control_request(&request, cluster, out, client, &options.stream).await;
+    }
+
+    // `CANCEL` finds the job by HiveCore's ID, leased or not.
+    let job_id = request
+        .header(JOB_ID_HEADER)
+        .map(|job_id| job_id.trim().to_string())
+        .filter(|job_id| !job_id.is_empty());
+    let lease = JobLease::take(&mut request, options.leases);
+    if let Some(model) = request
+        .extract_model()
+        .filter(|model| !cluster.allows_model(model))
+    {
+        warn!(
+            "Refusing request for model {} outside the {} allowlist",
+            model, cluster.name
+        );
+        match &lease {
+            Some(lease) => {
+                lease
+                    .nack(
+                        out,
+                        NackReason::ModelNotServed,
+                        "Model is not served to this cluster.",
+                    )
+                    .await?
+            }
+            None => {
+                write_http_response(
+                    out,
+                    "403 Forbidden",
+                    "Model is not served to this cluster.\n",
+                )
+                .await?
+            }
+        }
+        return Ok(false);
+    }
+
+    // A Docker upgrade holds the write lock; a leased job waits for it only as
+    // long as HiveCore keeps the lease.
+    let _read_guard = match &lease {
+        Some(lease) => match lease.remaining() {
+            Some(remaining) => match timeout(remaining, DOCKER_UPGRADE_LOCK.read()).await {
+                Ok(guard) if !lease.is_expired() => guard,
+                _ => {
+                    lease.nack(out, NackReason::LeaseExpired, "").await?;
+                    return Ok(false);
+                }
+            },
+            None => DOCKER_UPGRADE_LOCK.read().await,
+        },
+        None => DOCKER_UPGRADE_LOCK.read().await,
+    };
+
+    // Dropping the proxy future closes the backend request, which makes the
+    // backend stop generating.
+    let job = RunningJob::register(job_id.as_deref(), request.extract_model());
+    tokio::select! {
+        served = stream_response_to_proxy(request, cluster, out, client, &options.stream, lease.as_ref(), None) => served,
+        _ = job.cancelled() => {
+            info!("Cancelled job {}", job.id());
+            Err(JobCancelled.into())
+        }
     }
-    Ok(i32::from_be_bytes(len_buf) as usize)
 }
 
-pub fn read_next_message(stream: &mut TcpStream) -> Result<ProxyMessage> {
-    let message_length = read_next_message_length(stream)?;
-    let mut buffer = vec![0u8; message_length];
-    if let Err(e) = stream.read_exact(&mut buffer) {
-        error!("Error reading message from HiveCore: {}", e);
-        return Err(e.into());
+/// Serves `request` on a one-job-per-connection session while watching the
+/// connection: a `CANCEL` naming the job or a closed socket abort the job and
+/// the backend request with it.
+///
+/// Other messages that arrive meanwhile are answered once the response is
+/// complete, so the replies do not cut into it: `CANCEL` runs like any
+/// command, everything else is refused as busy.
+pub async fn serve_watching_core(
+    request: ProxyMessage,
+    cluster: &ClusterConfig,
+    stream: &mut CoreStream,
+    reader: &mut FrameReader,
+    client: &Client,
+    options: &ServeOptions,
+) -> Result<bool> {
+    let (mut core_rx, mut core_tx) = split(stream);
+    let mut replies = Vec::new();
+    let served = {
+        let served = serve_request(request, cluster, &mut core_tx, client, options);
+        tokio::pin!(served);
+        loop {
+            tokio::select! {
+                served = &mut served => break served,
+                frame = reader.read_frame(&mut core_rx) => {
+                    answer_during_job(frame, cluster, &mut replies, client, &options.stream).await?;
+                }
+            }
+        }
+    };
+    // A job that failed or was cancelled ends the session, which answers
+    // the messages as well.
+    if served.is_ok() && !replies.is_empty() {
+        core_tx.write_all(&replies).await?;
+        core_tx.flush().await?;
     }
+    served
+}
 
-    let raw_request = String::from_utf8_lossy(&buffer);
-    let raw_request = raw_request.into_owned();
-    Ok(ProxyMessage::from(raw_request))
+/// Answers a message HiveCore sent while a job runs on this connection. A
+/// `CANCEL` runs like on an idle connection and cancels the job it names,
+/// which may be this one. Commands and requests are refused as busy; other
+/// HIVE messages, like a late `PONG`, need no answer. A frame over the size
+/// limit is refused as on an idle connection; any other read error ends the
+/// session.
+async fn answer_during_job<W: AsyncWrite + Unpin>(
+    frame: Result<Vec<u8>>,
+    cluster: &ClusterConfig,
+    out: &mut W,
+    client: &Client,
+    config: &StreamConfig,
+) -> Result<()> {
+    let frame = match frame {
+        Ok(frame) => frame,
+        Err(e) if matches!(e.downcast_ref(), Some(FrameError::TooLarge { .. })) => {
+            warn!("Refusing request: {}", e);
+            return write_http_response(out, "413 Payload Too Large", &format!("{e}.\n")).await;
+        }
+        Err(e) => return Err(e.context("HiveCore closed the connection during a job")),
+    };
+    let message = match ProxyMessage::parse(&frame) {
+        Ok(message) => message,
+        Err(e) => {
+            warn!("Refusing request: {}", e);
+            return write_http_response(out, "400 Bad Request", &format!("{e}.\n")).await;
+        }
+    };
+    let request_id = match ControlRequest::from_message(&message) {
+        Ok(Some(control)) if control.command == "CANCEL" => {
+            handle_control_request(&message, cluster, out, client, config).await?;
+            return Ok(());
+        }
+        Ok(Some(control)) => control.request_id,
+        Ok(None) if message.protocol == "HIVE" => return Ok(()),
+        _ => None,
+    };
+    debug!(
+        "Refusing {} {} while a job is running",
+        message.method, message.uri
+    );
+    write_response(
+        out,
+        "409 Conflict",
+        TEXT_PLAIN,
+        "HiveNode is busy with a job on this connection.\n",
+        request_id.as_deref(),
+    )
+    .await
 }
 
-pub fn stream_response_to_proxy(
+/// Proxies `request` to the backend and streams the reply to HiveCore. A
+/// leased job is accepted before the backend is asked, since loading a model
+/// can take longer than the lease; until the first response byte it can
+/// still be handed back with `NACK`. Replies to control commands echo their
+/// request ID.
+pub async fn stream_response_to_proxy<W: AsyncWrite + Unpin>(
     request: ProxyMessage,
-    stream: &mut TcpStream,
+    cluster: &ClusterConfig,
+    stream: &mut W,
     client: &Client,
+    config: &StreamConfig,
+    lease: Option<&JobLease>,
+    request_id: Option<&str>,
 ) -> Result<bool> {
     let backend = get_backend()?;
     info!("Recieved {} request. {:#?}", backend.label(), request);
-    let response = make_backend_request(&request, client)?;
-    let response_code = response.status().as_u16();
+    if let Some(lease) = lease {
+        lease.accept(stream).await?;
+    }
+    let response = match (make_backend_request(&request, client).await, lease) {
+        (Ok(response), _) => response,
+        (Err(e), Some(lease)) => {
+            send_err_influx_with_req(&request, vec![], &format!("{e:#}"));
+            lease
+                .nack(stream, NackReason::BackendDown, &format!("{e:#}"))
+                .await?;
+            return Ok(false);
+        }
+        (Err(e), None) => {
+            write_backend_failure(stream, cluster, &request, &e).await?;
+            return Ok(false);
+        }
+    };
+    let status = response.status();
+    let response_code = status.as_u16();
+    let headers = response.headers().clone();
+    let framing = BodyFraming::of(&response);
+
+    // A leased job the backend failed is handed back, unless the reply is
+    // meant for the client; telling which takes the body.
+    let body = match lease {
+        Some(lease) if NackReason::may_hand_back(response_code) => {
+            let body = response.bytes().await.unwrap_or_default().to_vec();
+            let text = String::from_utf8_lossy(&body);
+            if let Some(reason) = NackReason::for_backend_status(response_code, &text) {
+                send_err_influx_with_req(&request, body.clone(), &reason.to_string());
+                lease.nack(stream, reason, &text).await?;
+                return Ok(false);
+            }
+            BackendBody::Read(body)
+        }
+        _ => BackendBody::Pending(response),
+    };
     let mut influx_stream: Vec<u8> = vec![];
 
     match response_code {
         200 => info!(
             "{} responded with: {} | Streaming back response...",
             backend.label(),
-            response.status()
+            status
         ),
         _ => warn!(
             "{} responded with: {} | Streaming back response...",
             backend.label(),
-            response.status()
+            status
         ),
     };
 
-    if let Err(e) = write_http_status_line(stream, &response, &mut influx_stream) {
+    if let Err(e) = write_http_status_line(stream, status, &mut influx_stream).await {
         let e_msg = format!("Error streaming status line to HiveCore: {}", e);
         send_err_influx_with_req(&request, influx_stream, &e_msg);
         return Err(anyhow!(e_msg));
     }
 
-    if let Err(e) = write_http_headers(stream, &response, &mut influx_stream) {
+    if let Err(e) =
+        write_http_headers(stream, &headers, framing, request_id, &mut influx_stream).await
+    {
         let e_msg = format!("Error streaming headers to HiveCore: {}", e);
         send_err_influx_with_req(&request, influx_stream, &e_msg);
         return Err(anyhow!(e_msg));
     }
 
-    if let Err(e) = stream_body(stream, response, &mut influx_stream) {
+    let streamed = match body {
+        BackendBody::Read(body) => {
+            write_read_body(stream, &body, framing, &mut influx_stream).await
+        }
+        BackendBody::Pending(response) => {
+            stream_body(stream, response, framing, config, &mut influx_stream).await
+        }
+    };
+    if let Err(e) = streamed {
         let e_msg = format!("Error streaming body to HiveCore: {}", e);
         send_err_influx_with_req(&request, influx_stream, &e_msg);
         return Err(anyhow!(e_msg));
@@ -220,76 +610,99 @@ pub fn stream_response_to_proxy(
 
     send_success_influx_with_req(&request, influx_stream, response_code);
     info!("Stream ended. Response done.");
+    if let Some(lease) = lease {
+        lease.done(stream).await?;
+    }
 
     Ok(request.modifies_poll())
 }
 
-fn stream_body(
-    stream: &mut TcpStream,
-    response: Response,
-    influx_stream: &mut Vec<u8>,
+/// Answers a request whose backend call failed before anything was streamed
+/// with a JSON error, so HiveCore and the client learn why instead of seeing
+/// the connection drop.
+async fn write_backend_failure<W: AsyncWrite + Unpin>(
+    stream: &mut W,
+    cluster: &ClusterConfig,
+    request: &ProxyMessage,
+    error: &anyhow::Error,
 ) -> Result<()> {
-    let mut response_reader = BufReader::new(response);
-
-    loop {
-        let mut chunk = Vec::new();
-        let bytes_read = response_reader.read_until(b'\n', &mut chunk)?;
-        if bytes_read == 0 {
-            break;
-        }
+    let (status, cause) = backend_failure_status(error);
+    let e_msg = format!("Backend request failed: {:#}", error);
+    error!("{}", e_msg);
+    let body = serde_json::json!({
+        "error": e_msg,
+        "cause": cause,
+        "node": cluster_node_name(&cluster.name).unwrap_or_else(|| "Unknown".to_string()),
+    })
+    .to_string();
+    write_json_response(stream, status, &body).await?;
+    send_err_influx_with_req(request, body.into_bytes(), &e_msg);
+    Ok(())
+}
 
-        let chunk_size = format!("{:X}\r\n", bytes_read).into_bytes();
-        stream.write_all(&chunk_size)?;
-        write_to_both_streams(stream, influx_stream, &chunk)?;
-        write_to_both_streams(stream, influx_stream, b"\r\n")?;
-        stream.flush()?;
+/// Picks the gateway status and a short cause for a failed backend call.
+fn backend_failure_status(error: &anyhow::Error) -> (&'static str, &'static str) {
+    let reqwest_error = error
+        .chain()
+        .find_map(|cause| cause.downcast_ref::<reqwest::Error>());
+    match reqwest_error {
+        Some(e) if e.is_timeout() => ("504 Gateway Timeout", "backend_timeout"),
+        Some(e) if e.is_connect() => ("503 Service Unavailable", "backend_unreachable"),
+        _ => ("502 Bad Gateway", "backend_error"),
     }
-    stream.write_all("0\r\n\r\n".as_bytes())?;
-    stream.flush()?;
-    Ok(())
 }
 
-/// Writes HTTP headers to both TCP stream and the influx stream, which is used for error reporting.
-fn write_http_headers(
-    stream: &mut TcpStream,
-    response: &Response,
+/// Writes HTTP headers to both HiveCore stream and the influx stream, which is used for error reporting.
+///
+/// The backend's `Content-Length` is kept when the body is forwarded as is;
+/// otherwise the body is chunked.
+async fn write_http_headers<W: AsyncWrite + Unpin>(
+    stream: &mut W,
+    headers: &HeaderMap,
+    framing: BodyFraming,
+    request_id: Option<&str>,
     influx_stream: &mut Vec<u8>,
 ) -> Result<()> {
-    for (key, value) in response.headers() {
-        if key.as_str().to_ascii_lowercase() != "transfer-encoding" {
-            let header_line = format!("{}: {}\r\n", key, value.to_str()?).into_bytes();
-            write_to_both_streams(stream, influx_stream, &header_line)?;
+    for (key, value) in headers {
+        let replaced = key == TRANSFER_ENCODING
+            || key == CONNECTION
+            || (key == CONTENT_LENGTH && framing == BodyFraming::Chunked);
+        if !replaced {
+            // Values are copied as bytes, since they need not be ASCII.
+            let mut header_line = format!("{key}: ").into_bytes();
+            header_line.extend_from_slice(value.as_bytes());
+            header_line.extend_from_slice(b"\r\n");
+            write_to_both_streams(stream, influx_stream, &header_line).await?;
         }
     }
-    write_to_both_streams(stream, influx_stream, b"Transfer-Encoding: chunked\r\n")?;
-    write_to_both_streams(stream, influx_stream, b"Connection: close\r\n")?;
-    write_to_both_streams(stream, influx_stream, b"\r\n")?;
-    stream.flush()?;
+    if let Some(request_id) = request_id {
+        let header_line = format!("{REQUEST_ID_HEADER}: {request_id}\r\n").into_bytes();
+        write_to_both_streams(stream, influx_stream, &header_line).await?;
+    }
+    if framing == BodyFraming::Chunked {
+        write_to_both_streams(stream, influx_stream, b"Transfer-Encoding: chunked\r\n").await?;
+    }
+    write_to_both_streams(stream, influx_stream, b"Connection: close\r\n").await?;
+    write_to_both_streams(stream, influx_stream, b"\r\n").await?;
+    stream.flush().await?;
     Ok(())
 }
 
-/// Write HTTP status line to both TCP stream and the influx stream, which is used for error reporting if the status is not 200.
-fn write_http_status_line(
-    stream: &mut TcpStream,
-    response: &Response,
+/// Write HTTP status line to both HiveCore stream and the influx stream, which is used for error reporting if the status is not 200.
+async fn write_http_status_line<W: AsyncWrite + Unpin>(
+    stream: &mut W,
+    status: StatusCode,
     influx_stream: &mut Vec<u8>,
 ) -> Result<()> {
     // Write the status line
     let status_line = format!(
         "HTTP/1.1 {} {}\r\n",
-        response.status(),
-        response.status().canonical_reason().unwrap_or("")
+        status,
+        status.canonical_reason().unwrap_or("")
     )
     .into_bytes();
-    write_to_both_streams(stream, influx_stream, &status_line)?;
-    stream.flush()?;
-    Ok(())
-}
-
-/// Writes to both streams simultaneously. Exists to reduce code duplication.
-fn write_to_both_streams(tcp: &mut TcpStream, second: &mut Vec<u8>, data: &[u8]) -> Result<()> {
-    tcp.write_all(data)?;
-    second.extend_from_slice(data);
+    write_to_both_streams(stream, influx_stream, &status_line).await?;
+    stream.flush().await?;
     Ok(())
 }
 
@@ -338,3 +751,131 @@ fn send_err_influx_with_req(req: &ProxyMessage, stream: Vec<u8>, err: &String) {
 
     log_influx(vec![data_point]);
 }
+
+#[cfg(test)]
+mod tests {
+    use hmac::{Hmac, Mac};
+    use sha2::Sha256;
+
+    use super::{answer_during_job, backend_failure_status, handle_control_request};
+    use crate::messages::proxy_message::ProxyMessage;
+    use crate::protocol::cluster::ClusterConfig;
+    use crate::protocol::control_auth::{
+        verify_control, NONCE_HEADER, SIGNATURE_HEADER, TIMESTAMP_HEADER,
+    };
+    use crate::protocol::frame::FrameError;
+    use crate::protocol::state::cluster_node_name;
+    use crate::protocol::streaming::StreamConfig;
+
+    fn cluster() -> ClusterConfig {
+        ClusterConfig {
+            name: "default".into(),
+            core_url: "core:7777".into(),
+            key: "worker-secret".into(),
+            concurrency: 1,
+            models: None,
+            core_public_key: None,
+        }
+    }
+
+    fn stream_config() -> StreamConfig {
+        StreamConfig {
+            flush_bytes: 16 * 1024,
+            flush_after: std::time::Duration::from_millis(5),
+        }
+    }
+
+    #[tokio::test]
+    async fn maps_refused_backend_to_service_unavailable() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        drop(listener);
+
+        let error = reqwest::Client::new()
+            .get(format!("http://{addr}/api/tags"))
+            .send()
+            .await
+            .unwrap_err();
+        let error = anyhow::Error::from(error).context("Error proxying request");
+        assert_eq!(
+            backend_failure_status(&error),
+            ("503 Service Unavailable", "backend_unreachable")
+        );
+        assert_eq!(
+            backend_failure_status(&anyhow::anyhow!("bad method")),
+            ("502 Bad Gateway", "backend_error")
+        );
+    }
+
+    #[tokio::test]
+    async fn refuses_signed_worker_command_requests() {
+        let cluster = cluster();
+        let mut message =
+            ProxyMessage::parse(b"POST /worker/command HTTP/1.1\r\n\r\nSTATUS").unwrap();
+        let timestamp = chrono::Utc::now().timestamp().to_string();
+        let node_name = cluster_node_name(&cluster.name).unwrap_or_default();
+        let payload = format!("{timestamp}\nhttp-1\n{node_name}\nPOST /worker/command\nSTATUS");
+        let mut mac = Hmac::<Sha256>::new_from_slice(cluster.key.as_bytes()).unwrap();
+        mac.update(payload.as_bytes());
+        message.headers.set(TIMESTAMP_HEADER, timestamp);
+        message.headers.set(NONCE_HEADER, "http-1");
+        message
+            .headers
+            .set(SIGNATURE_HEADER, hex::encode(mac.finalize().into_bytes()));
+
+        let mut out = Vec::new();
+        let client = reqwest::Client::new();
+        handle_control_request(&message, &cluster, &mut out, &client, &stream_config())
+            .await
+            .unwrap();
+        let reply = String::from_utf8(out).unwrap();
+        assert!(reply.starts_with("HTTP/1.1 403 Forbidden\r\n"), "{reply}");
+        assert!(reply.contains("only accepted as HIVE frames"), "{reply}");
+        // The signature itself is valid; only the path is refused.
+        assert!(verify_control(&message, &cluster).is_ok());
+    }
+
+    #[tokio::test]
+    async fn refuses_messages_during_a_job_as_busy() {
+        let cluster = cluster();
+        let client = reqwest::Client::new();
+        let config = stream_config();
+        let answer = |frame: anyhow::Result<Vec<u8>>| {
+            let (cluster, client, config) = (&cluster, &client, &config);
+            async move {
+                let mut out = Vec::new();
+                answer_during_job(frame, cluster, &mut out, client, config)
+                    .await
+                    .unwrap();
+                String::from_utf8(out).unwrap()
+            }
+        };
+
+        let reply = answer(Ok(
+            b"STATUS / HIVE\r\nX-Hive-Request-Id: s-1\r\n\r\n".to_vec()
+        ))
+        .await;
+        assert!(reply.starts_with("HTTP/1.1 409 Conflict\r\n"), "{reply}");
+        assert!(reply.contains("X-Hive-Request-Id: s-1\r\n"), "{reply}");
+
+        let reply = answer(Ok(b"POST /api/generate HTTP/1.1\r\n\r\n{}".to_vec())).await;
+        assert!(reply.starts_with("HTTP/1.1 409 Conflict\r\n"), "{reply}");
+
+        // An unsigned CANCEL is refused like on an idle connection.
+        let reply = answer(Ok(b"CANCEL job-1 HIVE\r\n\r\n".to_vec())).await;
+        assert!(reply.starts_with("HTTP/1.1 403 Forbidden\r\n"), "{reply}");
+
+        assert_eq!(answer(Ok(b"PONG 3 HIVE\r\n\r\n".to_vec())).await, "");
+
+        let too_large = FrameError::TooLarge {
+            length: 1000,
+            max: 8,
+            head: b"POST /ap".to_vec(),
+        };
+        let reply = answer(Err(too_large.into())).await;
+        assert!(
+            reply.starts_with("HTTP/1.1 413 Payload Too Large\r\n"),
+            "{reply}"
+        );
+    }
+}
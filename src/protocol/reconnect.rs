@@ -0,0 +1,251 @@
+use anyhow::Result;
+use log::{info, warn};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::time::{Duration, Instant};
+
+use super::env_util::env_u64;
+
+/// Source of time for the reconnect policy, so the backoff can be tested
+/// without sleeping.
+pub trait Clock {
+    fn now(&self) -> Instant;
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum FailureKind {
+    /// HiveCore unreachable, connection dropped, backend not ready, ...
+    Network,
+    /// HiveCore rejected authentication with a transient reason.
+    Auth,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum CircuitState {
+    /// Connected, or never failed.
+    Closed,
+    /// Waiting out a backoff delay.
+    Open,
+    /// The delay elapsed and a reconnect attempt is in progress.
+    HalfOpen,
+}
+
+#[derive(Clone, Debug)]
+pub struct ReconnectPolicy {
+    pub base: Duration,
+    pub max: Duration,
+    pub auth_base: Duration,
+    pub auth_max: Duration,
+    /// A session that stayed up this long resets the backoff.
+    pub stable_after: Duration,
+}
+
+impl ReconnectPolicy {
+    pub fn from_env() -> Result<Self> {
+        Ok(Self {
+            base: Duration::from_secs(env_u64("HIVE_RECONNECT_BASE_SECS", 1)?),
+            max: Duration::from_secs(env_u64("HIVE_RECONNECT_MAX_SECS", 60)?),
+            auth_base: Duration::from_secs(env_u64("HIVE_RECONNECT_AUTH_BASE_SECS", 30)?),
+            auth_max: Duration::from_secs(env_u64("HIVE_RECONNECT_AUTH_MAX_SECS", 900)?),
+            stable_after: Duration::from_secs(env_u64("HIVE_RECONNECT_STABLE_SECS", 60)?),
+        })
+    }
+
+    /// Upper bound of the jittered delay for the given failure count.
+    fn ceiling(&self, kind: FailureKind, failures: u32) -> Duration {
+        let (base, max) = match kind {
+            FailureKind::Network => (self.base, self.max),
+            FailureKind::Auth => (self.auth_base, self.auth_max),
+        };
+        base.saturating_mul(2u32.saturating_pow(failures.min(31)))
+            .min(max)
+    }
+}
+
+/// Exponential backoff with full jitter for one HiveCore connection slot.
+pub struct Reconnector<C: Clock = SystemClock> {
+    policy: ReconnectPolicy,
+    clock: C,
+    rng: StdRng,
+    failures: u32,
+    state: CircuitState,
+    connected_at: Option<Instant>,
+}
+
+impl Reconnector<SystemClock> {
+    pub fn new(policy: ReconnectPolicy) -> Self {
+        Self::with_clock(policy, SystemClock, StdRng::from_entropy())
+    }
+}
+
+impl<C: Clock> Reconnector<C> {
+    pub fn with_clock(policy: ReconnectPolicy, clock: C, rng: StdRng) -> Self {
+        Self {
+            policy,
+            clock,
+            rng,
+            failures: 0,
+            state: CircuitState::Closed,
+            connected_at: None,
+        }
+    }
+
+    /// Marks the start of a reconnect attempt after the delay elapsed.
+    pub fn on_attempt(&mut self) {
+        if self.state == CircuitState::Open {
+            self.state = CircuitState::HalfOpen;
+        }
+    }
+
+    /// Marks the connection as authenticated and serving.
+    pub fn on_connected(&mut self) {
+        if self.state != CircuitState::Closed {
+            info!(
+                "Connection to HiveCore restored after {} failures",
+                self.failures
+            );
+        }
+        self.state = CircuitState::Closed;
+        self.connected_at = Some(self.clock.now());
+    }
+
+    /// A session ended on purpose (e.g. `REBOOT`); reconnect soon.
+    pub fn on_session_end(&mut self) -> Duration {
+        self.reset_if_stable();
+        self.failures = 0;
+        self.open(FailureKind::Network)
+    }
+
+    /// Records a failure and returns how long to wait before the next attempt.
+    pub fn on_failure(&mut self, kind: FailureKind) -> Duration {
+        self.reset_if_stable();
+        let delay = self.open(kind);
+        self.failures = self.failures.saturating_add(1);
+        delay
+    }
+
+    fn reset_if_stable(&mut self) {
+        if let Some(connected_at) = self.connected_at.take() {
+            if self.clock.now().duration_since(connected_at) >= self.policy.stable_after {
+                self.failures = 0;
+            }
+        }
+    }
+
+    fn open(&mut self, kind: FailureKind) -> Duration {
+        let ceiling = self.policy.ceiling(kind, self.failures);
+        let delay = Duration::from_secs_f64(self.rng.gen_range(0.0..=ceiling.as_secs_f64()));
+        if self.failures > 0 {
+            warn!(
+                "Reconnect circuit open after {} consecutive {:?} failures (backoff cap {}s)",
+                self.failures + 1,
+                kind,
+                ceiling.as_secs()
+            );
+        }
+        self.state = CircuitState::Open;
+        delay
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CircuitState, Clock, FailureKind, ReconnectPolicy, Reconnector};
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+    use std::cell::Cell;
+    use std::rc::Rc;
+    use std::time::{Duration, Instant};
+
+    #[derive(Clone)]
+    struct FakeClock(Rc<Cell<Instant>>);
+
+    impl FakeClock {
+        fn advance(&self, by: Duration) {
+            self.0.set(self.0.get() + by);
+        }
+    }
+
+    impl Clock for FakeClock {
+        fn now(&self) -> Instant {
+            self.0.get()
+        }
+    }
+
+    fn policy() -> ReconnectPolicy {
+        ReconnectPolicy {
+            base: Duration::from_secs(1),
+            max: Duration::from_secs(60),
+            auth_base: Duration::from_secs(30),
+            auth_max: Duration::from_secs(900),
+            stable_after: Duration::from_secs(60),
+        }
+    }
+
+    fn reconnector() -> (Reconnector<FakeClock>, FakeClock) {
+        let clock = FakeClock(Rc::new(Cell::new(Instant::now())));
+        let reconnector =
+            Reconnector::with_clock(policy(), clock.clone(), StdRng::seed_from_u64(7));
+        (reconnector, clock)
+    }
+
+    #[test]
+    fn ceiling_grows_exponentially_up_to_max() {
+        let policy = policy();
+        assert_eq!(
+            policy.ceiling(FailureKind::Network, 0),
+            Duration::from_secs(1)
+        );
+        assert_eq!(
+            policy.ceiling(FailureKind::Network, 3),
+            Duration::from_secs(8)
+        );
+        assert_eq!(
+            policy.ceiling(FailureKind::Network, 40),
+            Duration::from_secs(60)
+        );
+        assert_eq!(
+            policy.ceiling(FailureKind::Auth, 1),
+            Duration::from_secs(60)
+        );
+    }
+
+    #[test]
+    fn delays_are_jittered_below_the_ceiling() {
+        let (mut reconnector, _) = reconnector();
+        for failures in 0..10 {
+            let delay = reconnector.on_failure(FailureKind::Network);
+            assert!(delay <= policy().ceiling(FailureKind::Network, failures));
+            assert_eq!(reconnector.state, CircuitState::Open);
+        }
+    }
+
+    #[test]
+    fn stable_connection_resets_backoff() {
+        let (mut reconnector, clock) = reconnector();
+        for _ in 0..5 {
+            reconnector.on_failure(FailureKind::Network);
+        }
+        reconnector.on_attempt();
+        assert_eq!(reconnector.state, CircuitState::HalfOpen);
+
+        reconnector.on_connected();
+        clock.advance(Duration::from_secs(5));
+        reconnector.on_failure(FailureKind::Network);
+        assert_eq!(reconnector.failures, 6);
+
+        reconnector.on_connected();
+        clock.advance(Duration::from_secs(61));
+        let delay = reconnector.on_failure(FailureKind::Network);
+        assert!(delay <= Duration::from_secs(1));
+        assert_eq!(reconnector.failures, 1);
+    }
+}
@@ -0,0 +1,579 @@
+use anyhow::{anyhow, Context, Result};
+use chrono::{DateTime, Utc};
+use log::{error, info, warn};
+use once_cell::sync::OnceCell;
+use reqwest::{Client, Url};
+use ring::signature::{UnparsedPublicKey, ED25519};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::env;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use tokio::time::sleep;
+
+use super::backend::backend_version;
+use super::env_util::env_secs;
+use super::events::{queue_event, CommandEvent, CommandOutcome};
+use super::state::{set_reboot, set_shutdown};
+
+/// Passes the node's auth nonce to the re-executed binary, so HiveCore sees
+/// the same node instance after an update.
+pub const NONCE_ENV: &str = "HIVE_NODE_NONCE";
+
+const NODE_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// The binary this process was started from. Read at startup, since after
+/// the swap `current_exe` may point at the replaced file.
+static EXE_PATH: OnceCell<PathBuf> = OnceCell::new();
+
+/// The auth nonce of this process, which the re-executed binary inherits.
+static NODE_NONCE: OnceCell<u64> = OnceCell::new();
+
+static UPDATE_RUNNING: AtomicBool = AtomicBool::new(false);
+static RESTART: AtomicBool = AtomicBool::new(false);
+
+/// Where `UPDATE_NODE` gets releases from and who must have signed them.
+pub struct UpdateConfig {
+    url: String,
+    public_key: Vec<u8>,
+}
+
+impl UpdateConfig {
+    /// Returns `None` unless both `HIVE_UPDATE_URL` and
+    /// `HIVE_UPDATE_PUBLIC_KEY` are set.
+    pub fn from_env() -> Result<Option<Self>> {
+        let (Ok(url), Ok(public_key)) = (
+            env::var("HIVE_UPDATE_URL"),
+            env::var("HIVE_UPDATE_PUBLIC_KEY"),
+        ) else {
+            return Ok(None);
+        };
+        if url.trim().is_empty() || public_key.trim().is_empty() {
+            return Ok(None);
+        }
+        let public_key = hex::decode(public_key.trim())
+            .ok()
+            .filter(|key| key.len() == 32)
+            .context("HIVE_UPDATE_PUBLIC_KEY must be a hex encoded ed25519 public key")?;
+        let url = url.trim().to_string();
+        release_url(&url)?;
+        Ok(Some(Self { url, public_key }))
+    }
+
+    /// The artifact URL, with `{version}` replaced by the requested version.
+    fn artifact_url(&self, version: Option<&str>) -> Result<String> {
+        match (self.url.contains("{version}"), version) {
+            (true, Some(version)) => Ok(self.url.replace("{version}", version)),
+            (true, None) => Err(anyhow!("UPDATE_NODE needs a version for {}", self.url)),
+            (false, _) => Ok(self.url.clone()),
+        }
+    }
+}
+
+/// Releases are downloaded over `https://` or `http://`, or read from a
+/// `file://` path. The parsed URL has its scheme in lower case, whatever
+/// case `HIVE_UPDATE_URL` used.
+fn release_url(url: &str) -> Result<Url> {
+    let parsed = Url::parse(url).ok();
+    match parsed {
+        Some(parsed) if matches!(parsed.scheme(), "https" | "http" | "file") => Ok(parsed),
+        _ => Err(anyhow!(
+            "HIVE_UPDATE_URL `{url}` must start with https://, http:// or file://"
+        )),
+    }
+}
+
+/// Arguments of `UPDATE_NODE`.
+#[derive(Debug, Deserialize)]
+pub struct UpdateArgs {
+    pub version: Option<String>,
+    /// Hex SHA-256 of the release artifact.
+    pub sha256: String,
+}
+
+/// Kept next to the binary while an update is being tried, so the new
+/// binary can confirm it and the old one can report a rollback.
+#[derive(Debug, Deserialize, Serialize)]
+struct UpdateState {
+    request_id: Option<String>,
+    /// The cluster that sent `UPDATE_NODE`, which the outcome is reported to.
+    #[serde(default)]
+    cluster: String,
+    /// The auth nonce handed to the new binary in `HIVE_NODE_NONCE`.
+    #[serde(default)]
+    nonce: Option<u64>,
+    started_at: String,
+    old_node_version: String,
+    phase: UpdatePhase,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(tag = "phase", rename_all = "snake_case")]
+enum UpdatePhase {
+    /// The new binary is installed; `started` is set once it ran.
+    Swapped { started: bool },
+    /// The previous binary was restored.
+    RolledBack {
+        new_node_version: String,
+        error: String,
+    },
+}
+
+fn exe_path() -> Result<&'static PathBuf> {
+    EXE_PATH.get_or_try_init(|| env::current_exe().context("Cannot locate the HiveNode binary"))
+}
+
+fn sibling(exe: &Path, suffix: &str) -> PathBuf {
+    let mut name = exe.as_os_str().to_owned();
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+fn state_path() -> Result<PathBuf> {
+    Ok(sibling(exe_path()?, ".update.json"))
+}
+
+fn read_state() -> Option<UpdateState> {
+    let path = state_path().ok()?;
+    let raw = std::fs::read(&path).ok()?;
+    match serde_json::from_slice(&raw) {
+        Ok(state) => Some(state),
+        Err(e) => {
+            warn!("Ignoring unreadable update state {}: {}", path.display(), e);
+            None
+        }
+    }
+}
+
+fn write_state(state: &UpdateState) -> Result<()> {
+    std::fs::write(state_path()?, serde_json::to_vec(state)?)?;
+    Ok(())
+}
+
+fn clear_state() {
+    if let Ok(path) = state_path() {
+        let _ = std::fs::remove_file(path);
+    }
+}
+
+/// Whether this process is a freshly installed binary that has not
+/// authenticated yet.
+pub fn update_pending() -> bool {
+    matches!(
+        read_state(),
+        Some(UpdateState {
+            phase: UpdatePhase::Swapped { .. },
+            ..
+        })
+    )
+}
+
+/// Whether the node should re-execute itself once it has drained.
+pub fn restart_requested() -> bool {
+    RESTART.load(Ordering::Relaxed)
+}
+
+/// Finishes an update at startup: the previous binary reports a rollback,
+/// and a new binary that already ran once without authenticating is rolled
+/// back, since it most likely crashed.
+pub async fn resume_update(nonce: u64) -> Result<()> {
+    exe_path()?;
+    let _ = NODE_NONCE.set(nonce);
+    let Some(mut state) = read_state() else {
+        return Ok(());
+    };
+    match state.phase {
+        UpdatePhase::RolledBack {
+            ref new_node_version,
+            ref error,
+        } => {
+            warn!("HiveNode {} was rolled back: {}", new_node_version, error);
+            let event = node_event(
+                &state,
+                CommandOutcome::Failed,
+                Some(error.clone()),
+                new_node_version.clone(),
+                &Client::new(),
+            )
+            .await;
+            queue_event(&state.cluster, event);
+            clear_state();
+        }
+        UpdatePhase::Swapped { started: true } => {
+            return Err(rollback_update(
+                "The new binary stopped before authenticating",
+                nonce,
+            ));
+        }
+        UpdatePhase::Swapped { started: false } => {
+            info!("Trying HiveNode {} after an update", NODE_VERSION);
+            state.phase = UpdatePhase::Swapped { started: true };
+            write_state(&state)?;
+        }
+    }
+    Ok(())
+}
+
+/// Whether this process is the binary `state` is trying: it was started
+/// with the nonce the update handed over and has marked itself as running.
+/// The draining process that installed the update must not confirm it.
+fn is_updated_process(state: &UpdateState, nonce: Option<u64>) -> bool {
+    state.nonce.is_some()
+        && state.nonce == nonce
+        && matches!(state.phase, UpdatePhase::Swapped { started: true })
+}
+
+/// Called after authenticating: an update being tried by this process is
+/// kept, and its success is reported.
+pub async fn confirm_update(client: &Client) {
+    let Some(state) = read_state() else {
+        return;
+    };
+    if !is_updated_process(&state, inherited_nonce()) {
+        return;
+    }
+    // Several connections may authenticate at once; the one that removes
+    // the state reports the update.
+    if state_path()
+        .and_then(|path| Ok(std::fs::remove_file(path)?))
+        .is_err()
+    {
+        return;
+    }
+    info!(
+        "HiveNode {} authenticated, keeping the update",
+        NODE_VERSION
+    );
+    if let Ok(exe) = exe_path() {
+        let _ = std::fs::remove_file(sibling(exe, ".previous"));
+    }
+    let event = node_event(
+        &state,
+        CommandOutcome::Succeeded,
+        None,
+        NODE_VERSION.to_string(),
+        client,
+    )
+    .await;
+    queue_event(&state.cluster, event);
+}
+
+/// Rolls back an update whose binary has not authenticated within
+/// `HIVE_UPDATE_CONFIRM_SECS`. Never resolves when no update is pending.
+pub async fn confirm_deadline(nonce: u64) -> Result<()> {
+    let Some(limit) = env_secs("HIVE_UPDATE_CONFIRM_SECS", 120)? else {
+        return futures::future::pending().await;
+    };
+    if !update_pending() {
+        return futures::future::pending().await;
+    }
+    sleep(limit).await;
+    if update_pending() {
+        return Err(rollback_update(
+            &format!("Not authenticated within {}s", limit.as_secs()),
+            nonce,
+        ));
+    }
+    futures::future::pending().await
+}
+
+/// Restores the previous binary and executes it. Only returns if that fails.
+pub fn rollback_update(reason: &str, nonce: u64) -> anyhow::Error {
+    error!("Rolling back HiveNode {}: {}", NODE_VERSION, reason);
+    let rollback = || -> Result<()> {
+        let exe = exe_path()?;
+        let mut state = read_state().context("No update to roll back")?;
+        std::fs::rename(sibling(exe, ".previous"), exe)
+            .context("Cannot restore the previous binary")?;
+        state.phase = UpdatePhase::RolledBack {
+            new_node_version: NODE_VERSION.to_string(),
+            error: reason.to_string(),
+        };
+        write_state(&state)
+    };
+    match rollback() {
+        Ok(()) => exec_node(nonce),
+        Err(e) => e,
+    }
+}
+
+async fn node_event(
+    state: &UpdateState,
+    outcome: CommandOutcome,
+    error: Option<String>,
+    new_node_version: String,
+    client: &Client,
+) -> CommandEvent {
+    let backend_version = backend_version(client).await;
+    let duration_ms = DateTime::parse_from_rfc3339(&state.started_at)
+        .map(|started| {
+            (Utc::now() - started.with_timezone(&Utc))
+                .num_milliseconds()
+                .max(0) as u64
+        })
+        .unwrap_or_default();
+    CommandEvent {
+        command: "UPDATE_NODE".into(),
+        request_id: state.request_id.clone(),
+        outcome,
+        error,
+        started_at: state.started_at.clone(),
+        duration_ms,
+        old_backend_version: backend_version.clone(),
+        new_backend_version: backend_version,
+        old_node_version: state.old_node_version.clone(),
+        new_node_version,
+    }
+}
+
+/// Downloads, verifies and installs a release in the background, then
+/// drains and restarts into it. A failure before the swap is reported as a
+/// completion event.
+pub fn start_node_update(
+    config: UpdateConfig,
+    args: UpdateArgs,
+    request_id: Option<String>,
+    cluster: String,
+) -> Result<()> {
+    let url = config.artifact_url(args.version.as_deref())?;
+    // The flag is only taken when the update goes ahead, since only the
+    // update task clears it.
+    if update_pending() || UPDATE_RUNNING.swap(true, Ordering::SeqCst) {
+        return Err(anyhow!("A HiveNode update is already in progress"));
+    }
+    let state = UpdateState {
+        request_id,
+        cluster,
+        nonce: NODE_NONCE.get().copied(),
+        started_at: Utc::now().to_rfc3339(),
+        old_node_version: NODE_VERSION.to_string(),
+        phase: UpdatePhase::Swapped { started: false },
+    };
+    tokio::spawn(async move {
+        warn!("Updating HiveNode from {}", url);
+        match install_release(&config, &url, &args.sha256, &state).await {
+            Ok(()) => {
+                info!("HiveNode update installed, restarting once drained");
+                RESTART.store(true, Ordering::Relaxed);
+                set_shutdown(true);
+            }
+            Err(e) => {
+                error!("Failed to update HiveNode: {:#}", e);
+                let event = node_event(
+                    &state,
+                    CommandOutcome::Failed,
+                    Some(format!("{e:#}")),
+                    NODE_VERSION.to_string(),
+                    &Client::new(),
+                )
+                .await;
+                queue_event(&state.cluster, event);
+                UPDATE_RUNNING.store(false, Ordering::SeqCst);
+                // Reconnect so the failure is reported.
+                set_reboot(true);
+            }
+        }
+    });
+    Ok(())
+}
+
+async fn install_release(
+    config: &UpdateConfig,
+    url: &str,
+    sha256: &str,
+    state: &UpdateState,
+) -> Result<()> {
+    let artifact = fetch(url).await?;
+    let signature = fetch(&format!("{url}.sig")).await?;
+    verify_release(&artifact, sha256, &signature, &config.public_key)?;
+
+    let exe = exe_path()?;
+    let download = sibling(exe, ".download");
+    std::fs::write(&download, &artifact).context("Cannot write the new binary")?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(&download, std::fs::Permissions::from_mode(0o755))?;
+    }
+    std::fs::copy(exe, sibling(exe, ".previous")).context("Cannot keep the current binary")?;
+    write_state(state)?;
+    // Renaming within one directory replaces the binary atomically.
+    if let Err(e) = std::fs::rename(&download, exe) {
+        clear_state();
+        return Err(e).context("Cannot replace the HiveNode binary");
+    }
+    Ok(())
+}
+
+/// Reads `https://`, `http://` and `file://` URLs.
+async fn fetch(url: &str) -> Result<Vec<u8>> {
+    let parsed = release_url(url)?;
+    if parsed.scheme() == "file" {
+        let path = parsed
+            .to_file_path()
+            .map_err(|_| anyhow!("{url} is not a local path"))?;
+        return tokio::fs::read(path)
+            .await
+            .with_context(|| format!("Cannot read {url}"));
+    }
+    let response = Client::new()
+        .get(parsed)
+        .timeout(Duration::from_secs(300))
+        .send()
+        .await
+        .with_context(|| format!("Cannot download {url}"))?
+        .error_for_status()?;
+    Ok(response.bytes().await?.to_vec())
+}
+
+/// Checks the artifact against the expected SHA-256 and its ed25519
+/// signature, given raw or hex encoded.
+fn verify_release(
+    artifact: &[u8],
+    sha256: &str,
+    signature: &[u8],
+    public_key: &[u8],
+) -> Result<()> {
+    let digest = hex::encode(Sha256::digest(artifact));
+    if !digest.eq_ignore_ascii_case(sha256.trim()) {
+        return Err(anyhow!(
+            "SHA-256 mismatch: expected {}, got {}",
+            sha256.trim(),
+            digest
+        ));
+    }
+    let signature = match signature.len() {
+        64 => signature.to_vec(),
+        _ => hex::decode(String::from_utf8_lossy(signature).trim())
+            .context("Release signature is neither raw nor hex")?,
+    };
+    UnparsedPublicKey::new(&ED25519, public_key)
+        .verify(artifact, &signature)
+        .map_err(|_| anyhow!("Release signature does not verify"))
+}
+
+/// Replaces this process with the installed binary, keeping the arguments,
+/// the environment and the auth nonce. Only returns if that fails.
+pub fn exec_node(nonce: u64) -> anyhow::Error {
+    let exe = match exe_path() {
+        Ok(exe) => exe,
+        Err(e) => return e,
+    };
+    info!("Restarting {}", exe.display());
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::CommandExt;
+        let e = std::process::Command::new(exe)
+            .args(env::args_os().skip(1))
+            .env(NONCE_ENV, nonce.to_string())
+            .exec();
+        anyhow!(e).context("Cannot restart HiveNode")
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = nonce;
+        anyhow!("Restarting HiveNode is only supported on Unix")
+    }
+}
+
+/// The nonce of the process this one replaced, if any.
+pub fn inherited_nonce() -> Option<u64> {
+    env::var(NONCE_ENV).ok()?.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use ring::rand::SystemRandom;
+    use ring::signature::{Ed25519KeyPair, KeyPair};
+    use sha2::{Digest, Sha256};
+    use tokio::io::AsyncReadExt;
+
+    use super::{is_updated_process, release_url, verify_release, UpdatePhase, UpdateState};
+
+    #[test]
+    fn verifies_hash_and_signature() {
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).unwrap();
+        let key = Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).unwrap();
+        let artifact = b"hive_node release";
+        let sha256 = hex::encode(Sha256::digest(artifact));
+        let signature = key.sign(artifact);
+        let public_key = key.public_key().as_ref();
+
+        assert!(verify_release(artifact, &sha256, signature.as_ref(), public_key).is_ok());
+        let hex_signature = hex::encode(signature.as_ref());
+        assert!(verify_release(artifact, &sha256, hex_signature.as_bytes(), public_key).is_ok());
+
+        assert!(verify_release(b"tampered", &sha256, signature.as_ref(), public_key).is_err());
+        let other = hex::encode(Sha256::digest(b"tampered"));
+        assert!(verify_release(b"tampered", &other, signature.as_ref(), public_key).is_err());
+    }
+
+    #[test]
+    fn accepts_https_http_and_file_release_urls() {
+        assert!(release_url("https://releases.example/hive_node-{version}").is_ok());
+        assert_eq!(
+            release_url("HTTP://releases.example/hive_node")
+                .unwrap()
+                .scheme(),
+            "http"
+        );
+        assert!(release_url("file:///opt/hive/hive_node").is_ok());
+        assert!(release_url("ftp://releases.example/hive_node").is_err());
+        assert!(release_url("releases.example/hive_node").is_err());
+    }
+
+    #[test]
+    fn only_the_updated_process_confirms() {
+        let state = |started| UpdateState {
+            request_id: None,
+            cluster: "main".into(),
+            nonce: Some(7),
+            started_at: "2024-01-01T00:00:00Z".into(),
+            old_node_version: "0.1.0".into(),
+            phase: UpdatePhase::Swapped { started },
+        };
+        assert!(is_updated_process(&state(true), Some(7)));
+        // The process that installed the update keeps draining with the
+        // state written, before the new binary marks it as started.
+        assert!(!is_updated_process(&state(false), Some(7)));
+        assert!(!is_updated_process(&state(true), Some(8)));
+        assert!(!is_updated_process(&state(true), None));
+        let unknown = UpdateState {
+            nonce: None,
+            ..state(true)
+        };
+        assert!(!is_updated_process(&unknown, None));
+    }
+
+    #[tokio::test]
+    async fn reads_file_urls_in_any_case() {
+        let path = std::env::temp_dir().join(format!("hive_node-fetch-{}", std::process::id()));
+        std::fs::write(&path, b"release").unwrap();
+        let fetched = super::fetch(&format!("FILE://{}", path.display())).await;
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(fetched.unwrap(), b"release");
+    }
+
+    #[tokio::test]
+    async fn downloads_over_https() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("https://{}/hive_node", listener.local_addr().unwrap());
+        let server = tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut record_type = [0u8; 1];
+            socket.read_exact(&mut record_type).await.unwrap();
+            record_type[0]
+        });
+
+        // The server never answers the handshake, so the download fails. Without
+        // TLS support reqwest would refuse the scheme before connecting.
+        assert!(super::fetch(&url).await.is_err());
+        // 0x16 starts a TLS handshake record.
+        let record_type = tokio::time::timeout(std::time::Duration::from_secs(5), server)
+            .await
+            .expect("reqwest did not connect")
+            .unwrap();
+        assert_eq!(record_type, 0x16);
+    }
+}
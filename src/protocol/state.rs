@@ -1,29 +1,76 @@
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
 
 use anyhow::Result;
 use chrono::{DateTime, Days, Utc};
 use lazy_static::lazy_static;
-use reqwest::blocking::Client;
+use reqwest::Client;
+use tokio::sync::{watch, Notify};
 
 use crate::models::poller::Poller;
 
-use super::backend::discover_models;
+use super::backend::{discover_models, get_backend};
+use super::cluster::ClusterConfig;
 
 lazy_static! {
     static ref LAST_REFRESH: Arc<RwLock<DateTime<Utc>>> = Arc::new(RwLock::new(Utc::now()));
-    static ref NODE_NAME: Arc<RwLock<String>> = Arc::new(RwLock::new(String::from("Unknown")));
+    static ref NODE_NAMES: Arc<RwLock<BTreeMap<String, String>>> =
+        Arc::new(RwLock::new(BTreeMap::new()));
     static ref REBOOT: Arc<RwLock<bool>> = Arc::new(RwLock::new(false));
     static ref SHUTDOWN: Arc<RwLock<bool>> = Arc::new(RwLock::new(false));
+    static ref SESSION_END: Notify = Notify::new();
+    static ref PAUSED: watch::Sender<bool> = watch::Sender::new(false);
+    static ref STARTED: Instant = Instant::now();
+}
+
+/// Authenticated HiveCore connections across all clusters.
+static ACTIVE_CONNECTIONS: AtomicUsize = AtomicUsize::new(0);
+
+/// Records the process start for `uptime`.
+pub fn mark_started() {
+    lazy_static::initialize(&STARTED);
+}
+
+pub fn uptime() -> Duration {
+    STARTED.elapsed()
+}
+
+/// Counts an authenticated connection while it is alive.
+pub struct ActiveConnection(());
+
+impl ActiveConnection {
+    pub fn register() -> Self {
+        ACTIVE_CONNECTIONS.fetch_add(1, Ordering::Relaxed);
+        Self(())
+    }
+}
+
+impl Drop for ActiveConnection {
+    fn drop(&mut self) {
+        ACTIVE_CONNECTIONS.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+pub fn active_connections() -> usize {
+    ACTIVE_CONNECTIONS.load(Ordering::Relaxed)
 }
 
 pub fn set_reboot(b: bool) {
     let mut rbt = REBOOT.write().unwrap();
     *rbt = b;
+    if b {
+        SESSION_END.notify_waiters();
+    }
 }
 
 pub fn set_shutdown(b: bool) {
     let mut sht = SHUTDOWN.write().unwrap();
     *sht = b;
+    if b {
+        SESSION_END.notify_waiters();
+    }
 }
 
 pub fn get_reboot() -> bool {
@@ -34,13 +81,66 @@ pub fn get_shutdown() -> bool {
     *SHUTDOWN.read().unwrap()
 }
 
-pub fn set_node_name(name: String) {
-    let mut global_name = NODE_NAME.write().unwrap();
-    *global_name = name;
+/// Pausing keeps the connections to HiveCore but stops polling for work.
+pub fn set_paused(b: bool) {
+    PAUSED.send_replace(b);
 }
 
+pub fn is_paused() -> bool {
+    *PAUSED.borrow()
+}
+
+/// Resolves once the paused state is `paused`.
+pub async fn paused_becomes(paused: bool) {
+    // The sender is a static, so waiting cannot fail.
+    let _ = PAUSED.subscribe().wait_for(|state| *state == paused).await;
+}
+
+/// Stores the node name HiveCore assigned to this node in `cluster`.
+pub fn set_node_name(cluster: &str, name: String) {
+    let mut names = NODE_NAMES.write().unwrap();
+    names.insert(cluster.to_string(), name);
+}
+
+/// The name HiveCore gave this node in `cluster`.
+pub fn cluster_node_name(cluster: &str) -> Option<String> {
+    NODE_NAMES.read().unwrap().get(cluster).cloned()
+}
+
+/// The authenticated node names, comma-separated when serving several clusters.
 pub fn get_node_name() -> String {
-    NODE_NAME.read().unwrap().to_string()
+    let names = NODE_NAMES.read().unwrap();
+    if names.is_empty() {
+        return String::from("Unknown");
+    }
+    names.values().cloned().collect::<Vec<_>>().join(",")
+}
+
+/// Resolves once a reboot or shutdown has been requested, so idle sessions
+/// end without waiting for their next message.
+pub async fn session_end_requested() {
+    let notified = SESSION_END.notified();
+    tokio::pin!(notified);
+    notified.as_mut().enable();
+    if get_reboot() || get_shutdown() {
+        return;
+    }
+    // Not re-checking the flags afterwards is deliberate: another connection
+    // may already have cleared `REBOOT` before this task runs again.
+    notified.await;
+}
+
+/// Resolves once a shutdown has been requested.
+pub async fn shutdown_requested() {
+    loop {
+        let notified = SESSION_END.notified();
+        tokio::pin!(notified);
+        notified.as_mut().enable();
+        if get_shutdown() {
+            return;
+        }
+        notified.await;
+    }
 }
 
 pub fn notify_refresh() {
@@ -56,12 +156,56 @@ pub fn get_last_refresh() -> DateTime<Utc> {
     *LAST_REFRESH.read().unwrap()
 }
 
-pub fn refresh_poll_models(
-    client: &Client,
-    local_last_refresh: &mut DateTime<Utc>,
-    models: &mut String,
-) -> Result<()> {
-    *models = Poller::from(discover_models(client)?).get_models_target();
-    *local_last_refresh = get_last_refresh();
-    Ok(())
+/// What a session advertises when it polls HiveCore for work.
+pub struct PollState {
+    models: String,
+    local_refresh: DateTime<Utc>,
+    optimized: bool,
+}
+
+impl PollState {
+    pub fn new() -> Self {
+        Self {
+            models: "/".to_string(),
+            local_refresh: init_local_time(),
+            optimized: false,
+        }
+    }
+
+    /// Whether the local models changed since this session last listed them.
+    pub fn is_stale(&self) -> bool {
+        get_last_refresh() > self.local_refresh
+    }
+
+    pub async fn refresh(&mut self, client: &Client, cluster: &ClusterConfig) -> Result<()> {
+        self.models =
+            Poller::from(cluster.filter_models(discover_models(client).await?)).get_models_target();
+        self.local_refresh = get_last_refresh();
+        self.optimized = false;
+        Ok(())
+    }
+
+    /// The next poll line.
+    ///
+    /// polling with "-" will tell the HiveCore to take the last seen
+    /// set of models as the possible tags. The Core will optimize the
+    /// sequence in which the models are polled based on the previously
+    /// handled work.
+    /// if the last work was using model X, it will prioratize the model
+    /// X work requests to handle. This minimizes the amount of switching
+    /// of models in the worker VRAM.
+    /// requres the worker to have previously sent the tags to the core,
+    /// so that the core has the list to work with
+    /// However, polling with X;Y;Z will set the sequence of models in
+    /// which the work is polled
+    pub fn next_poll(&mut self) -> Result<String> {
+        let poll_command = get_backend()?.poll_command();
+        let poll_target = if self.optimized {
+            format!("{poll_command} - HIVE\r\n")
+        } else {
+            format!("{poll_command} {} HIVE\r\n", self.models)
+        };
+        self.optimized = true;
+        Ok(poll_target)
+    }
 }
@@ -0,0 +1,234 @@
+use anyhow::{anyhow, Result};
+use reqwest::header::TRANSFER_ENCODING;
+use reqwest::Response;
+use std::time::Duration;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio::time::{timeout_at, Instant};
+
+use super::env_util::env_u64;
+
+/// Most response bytes kept for the InfluxDB log of a request.
+const CAPTURE_BYTES: usize = 64 * 1024;
+
+#[derive(Clone, Debug)]
+pub struct StreamConfig {
+    /// Buffered bytes that are sent at once. The buffer never grows past it.
+    pub flush_bytes: usize,
+    /// How long buffered bytes wait for more before they are sent. With zero
+    /// they are sent as soon as the backend has nothing more ready.
+    pub flush_after: Duration,
+}
+
+impl StreamConfig {
+    pub fn from_env() -> Result<Self> {
+        let flush_bytes = env_u64("HIVE_STREAM_FLUSH_BYTES", 16 * 1024)?;
+        if flush_bytes == 0 {
+            return Err(anyhow!("HIVE_STREAM_FLUSH_BYTES must be at least 1"));
+        }
+        Ok(Self {
+            flush_bytes: usize::try_from(flush_bytes).unwrap_or(usize::MAX),
+            flush_after: Duration::from_millis(env_u64("HIVE_STREAM_FLUSH_MS", 5)?),
+        })
+    }
+}
+
+/// How a response body is framed towards HiveCore.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum BodyFraming {
+    /// The backend sent a `Content-Length`, which is kept and the body
+    /// forwarded as is.
+    Length(u64),
+    /// HTTP chunks, for bodies whose length is not known up front.
+    Chunked,
+}
+
+impl BodyFraming {
+    pub fn of(response: &Response) -> Self {
+        match response.content_length() {
+            Some(length) if !response.headers().contains_key(TRANSFER_ENCODING) => {
+                Self::Length(length)
+            }
+            _ => Self::Chunked,
+        }
+    }
+}
+
+/// A backend body that is still to be streamed, or was already read.
+pub enum BackendBody {
+    Pending(Response),
+    Read(Vec<u8>),
+}
+
+/// Forwards the backend body as it arrives, whatever its content. Reads are
+/// gathered in a buffer of at most `flush_bytes` that is sent when full or
+/// once its oldest byte has waited `flush_after`, so token streams keep
+/// their latency and large bodies go out in few writes.
+pub async fn stream_body<W: AsyncWrite + Unpin>(
+    stream: &mut W,
+    mut response: Response,
+    framing: BodyFraming,
+    config: &StreamConfig,
+    influx_stream: &mut Vec<u8>,
+) -> Result<()> {
+    let mut buffer = Vec::with_capacity(config.flush_bytes);
+    let mut deadline = None;
+    loop {
+        // Waiting for the next read is cancel safe, so it can race the
+        // deadline of the buffered bytes.
+        let next = match deadline {
+            Some(at) => match timeout_at(at, response.chunk()).await {
+                Ok(next) => next?,
+                Err(_) => {
+                    write_body(stream, framing, influx_stream, &buffer).await?;
+                    buffer.clear();
+                    deadline = None;
+                    continue;
+                }
+            },
+            None => response.chunk().await?,
+        };
+        let Some(bytes) = next else {
+            break;
+        };
+
+        let mut rest = &bytes[..];
+        while !rest.is_empty() {
+            let taken = rest.len().min(config.flush_bytes - buffer.len());
+            buffer.extend_from_slice(&rest[..taken]);
+            rest = &rest[taken..];
+            if buffer.len() == config.flush_bytes {
+                write_body(stream, framing, influx_stream, &buffer).await?;
+                buffer.clear();
+                deadline = None;
+            }
+        }
+        if !buffer.is_empty() && deadline.is_none() {
+            deadline = Some(Instant::now() + config.flush_after);
+        }
+    }
+
+    if !buffer.is_empty() {
+        write_body(stream, framing, influx_stream, &buffer).await?;
+    }
+    end_body(stream, framing).await
+}
+
+/// Forwards a backend body that was already read, e.g. to look at an error
+/// before relaying it.
+pub async fn write_read_body<W: AsyncWrite + Unpin>(
+    stream: &mut W,
+    body: &[u8],
+    framing: BodyFraming,
+    influx_stream: &mut Vec<u8>,
+) -> Result<()> {
+    if !body.is_empty() {
+        write_body(stream, framing, influx_stream, body).await?;
+    }
+    end_body(stream, framing).await
+}
+
+async fn end_body<W: AsyncWrite + Unpin>(stream: &mut W, framing: BodyFraming) -> Result<()> {
+    if framing == BodyFraming::Chunked {
+        stream.write_all(b"0\r\n\r\n").await?;
+    }
+    stream.flush().await?;
+    Ok(())
+}
+
+async fn write_body<W: AsyncWrite + Unpin>(
+    stream: &mut W,
+    framing: BodyFraming,
+    influx_stream: &mut Vec<u8>,
+    data: &[u8],
+) -> Result<()> {
+    match framing {
+        BodyFraming::Length(_) => write_to_both_streams(stream, influx_stream, data).await?,
+        BodyFraming::Chunked => {
+            let chunk_size = format!("{:X}\r\n", data.len()).into_bytes();
+            stream.write_all(&chunk_size).await?;
+            write_to_both_streams(stream, influx_stream, data).await?;
+            write_to_both_streams(stream, influx_stream, b"\r\n").await?;
+        }
+    }
+    stream.flush().await?;
+    Ok(())
+}
+
+/// Writes to HiveCore and keeps the start of what was written in `second`,
+/// the copy logged to InfluxDB.
+pub async fn write_to_both_streams<W: AsyncWrite + Unpin>(
+    tcp: &mut W,
+    second: &mut Vec<u8>,
+    data: &[u8],
+) -> Result<()> {
+    tcp.write_all(data).await?;
+    let kept = data.len().min(CAPTURE_BYTES.saturating_sub(second.len()));
+    second.extend_from_slice(&data[..kept]);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{stream_body, BodyFraming, StreamConfig};
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    /// Serves one response written in `parts`, pausing between them.
+    async fn backend(parts: Vec<&'static [u8]>) -> reqwest::Response {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut request = [0u8; 1024];
+            let _ = socket.read(&mut request).await.unwrap();
+            for part in parts {
+                socket.write_all(part).await.unwrap();
+                socket.flush().await.unwrap();
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+        });
+        reqwest::get(format!("http://{addr}/")).await.unwrap()
+    }
+
+    async fn relay(response: reqwest::Response, flush_bytes: usize) -> (BodyFraming, Vec<u8>) {
+        let framing = BodyFraming::of(&response);
+        let config = StreamConfig {
+            flush_bytes,
+            flush_after: Duration::from_millis(5),
+        };
+        let mut out = Vec::new();
+        stream_body(&mut out, response, framing, &config, &mut Vec::new())
+            .await
+            .unwrap();
+        (framing, out)
+    }
+
+    #[tokio::test]
+    async fn keeps_the_backend_content_length() {
+        let response = backend(vec![
+            b"HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\n{\"a\":",
+            b"\"bc\"}",
+        ])
+        .await;
+
+        let (framing, out) = relay(response, 4).await;
+        assert_eq!(framing, BodyFraming::Length(10));
+        assert_eq!(out, b"{\"a\":\"bc\"}");
+    }
+
+    #[tokio::test]
+    async fn flushes_chunks_by_size_and_time() {
+        let response = backend(vec![
+            b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n",
+            b"6\r\nabcdef\r\n",
+            b"2\r\ngh\r\n0\r\n\r\n",
+        ])
+        .await;
+
+        // `abcd` fills the buffer, `ef` is sent after 5ms, `gh` comes 50ms later.
+        let (framing, out) = relay(response, 4).await;
+        assert_eq!(framing, BodyFraming::Chunked);
+        assert_eq!(out, b"4\r\nabcd\r\n2\r\nef\r\n2\r\ngh\r\n0\r\n\r\n");
+    }
+}
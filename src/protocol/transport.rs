@@ -0,0 +1,423 @@
+use anyhow::{anyhow, Context as _, Result};
+use log::info;
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::client::WebPkiServerVerifier;
+use rustls::crypto::{verify_tls12_signature, verify_tls13_signature, CryptoProvider};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use rustls::{ClientConfig, DigitallySignedStruct, RootCertStore, SignatureScheme};
+use sha2::{Digest, Sha256};
+use std::env;
+use std::fs::File;
+use std::future::Future;
+use std::io::{self, BufReader};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::{lookup_host, TcpStream};
+use tokio::time::{sleep, timeout, Sleep};
+use tokio_rustls::client::TlsStream;
+use tokio_rustls::TlsConnector;
+
+use super::env_util::env_flag;
+
+/// Connection to HiveCore, either plain TCP or wrapped in TLS.
+///
+/// It implements `AsyncRead` and `AsyncWrite`, so the authentication, polling
+/// and response streaming code does not need to know which one it talks to.
+/// Writes that make no progress for the configured write timeout fail with
+/// `TimedOut` instead of hanging on a peer that stopped reading.
+pub struct CoreStream {
+    io: CoreIo,
+    write_timeout: Option<Duration>,
+    write_deadline: Option<Pin<Box<Sleep>>>,
+}
+
+enum CoreIo {
+    Plain(TcpStream),
+    Tls(Box<TlsStream<TcpStream>>),
+}
+
+impl CoreStream {
+    fn new(io: CoreIo) -> Self {
+        Self {
+            io,
+            write_timeout: None,
+            write_deadline: None,
+        }
+    }
+
+    /// The underlying socket, used to tune keepalive.
+    pub fn tcp(&self) -> &TcpStream {
+        match &self.io {
+            CoreIo::Plain(stream) => stream,
+            CoreIo::Tls(stream) => stream.get_ref().0,
+        }
+    }
+
+    pub fn set_write_timeout(&mut self, timeout: Option<Duration>) {
+        self.write_timeout = timeout;
+        self.write_deadline = None;
+    }
+
+    /// Polls a write-side operation, failing it once it stayed pending for
+    /// longer than the write timeout.
+    fn poll_write_op<T>(
+        &mut self,
+        cx: &mut Context<'_>,
+        op: impl FnOnce(Pin<&mut CoreIo>, &mut Context<'_>) -> Poll<io::Result<T>>,
+    ) -> Poll<io::Result<T>> {
+        if let Poll::Ready(result) = op(Pin::new(&mut self.io), cx) {
+            self.write_deadline = None;
+            return Poll::Ready(result);
+        }
+
+        let Some(write_timeout) = self.write_timeout else {
+            return Poll::Pending;
+        };
+        let deadline = self
+            .write_deadline
+            .get_or_insert_with(|| Box::pin(sleep(write_timeout)));
+        match deadline.as_mut().poll(cx) {
+            Poll::Ready(()) => {
+                self.write_deadline = None;
+                Poll::Ready(Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "write to HiveCore timed out",
+                )))
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+impl AsyncRead for CoreStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().io).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for CoreStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.get_mut()
+            .poll_write_op(cx, |io, cx| io.poll_write(cx, buf))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.get_mut().poll_write_op(cx, |io, cx| io.poll_flush(cx))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.get_mut()
+            .poll_write_op(cx, |io, cx| io.poll_shutdown(cx))
+    }
+}
+
+impl AsyncRead for CoreIo {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Plain(stream) => Pin::new(stream).poll_read(cx, buf),
+            Self::Tls(stream) => Pin::new(stream.as_mut()).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for CoreIo {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Self::Plain(stream) => Pin::new(stream).poll_write(cx, buf),
+            Self::Tls(stream) => Pin::new(stream.as_mut()).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Plain(stream) => Pin::new(stream).poll_flush(cx),
+            Self::Tls(stream) => Pin::new(stream.as_mut()).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Plain(stream) => Pin::new(stream).poll_shutdown(cx),
+            Self::Tls(stream) => Pin::new(stream.as_mut()).poll_shutdown(cx),
+        }
+    }
+}
+
+/// Opens a connection to HiveCore at `addr` (`host:port`), negotiating TLS
+/// when `tls` is set.
+pub async fn connect_core(addr: &str, tls: bool, connect_timeout: Duration) -> Result<CoreStream> {
+    let tcp = connect_tcp(addr, connect_timeout).await?;
+    if !tls {
+        return Ok(CoreStream::new(CoreIo::Plain(tcp)));
+    }
+
+    let connector = TlsConnector::from(Arc::new(build_tls_config()?));
+    let server_name = tls_server_name(addr)?;
+
+    // Finish the handshake now so certificate problems surface as connection
+    // errors instead of failing the first AUTH write.
+    let stream = timeout(connect_timeout, connector.connect(server_name, tcp))
+        .await
+        .map_err(|_| anyhow!("TLS handshake with HiveCore timed out"))?
+        .context("TLS handshake with HiveCore failed")?;
+    info!("Established TLS session with HiveCore at {addr}");
+
+    Ok(CoreStream::new(CoreIo::Tls(Box::new(stream))))
+}
+
+/// Connects to the first address `addr` resolves to that accepts within `connect_timeout`.
+pub async fn connect_tcp(addr: &str, connect_timeout: Duration) -> io::Result<TcpStream> {
+    let mut last_error = None;
+    for socket_addr in lookup_host(addr).await? {
+        match timeout(connect_timeout, TcpStream::connect(socket_addr)).await {
+            Ok(Ok(stream)) => return Ok(stream),
+            Ok(Err(e)) => last_error = Some(e),
+            Err(_) => last_error = Some(io::Error::from(io::ErrorKind::TimedOut)),
+        }
+    }
+    Err(last_error.unwrap_or_else(|| {
+        io::Error::new(io::ErrorKind::NotFound, format!("{addr} did not resolve"))
+    }))
+}
+
+/// Whether the HiveCore connection should be wrapped in TLS, either because the
+/// URL uses an `https://`/`tls://` scheme or because `HIVE_CORE_TLS` is set.
+pub fn core_uses_tls(raw_url: &str) -> bool {
+    let trimmed = raw_url.trim();
+    trimmed.starts_with("https://") || trimmed.starts_with("tls://") || env_flag("HIVE_CORE_TLS")
+}
+
+fn tls_server_name(addr: &str) -> Result<ServerName<'static>> {
+    let name = match env::var("HIVE_TLS_SERVER_NAME") {
+        Ok(name) if !name.trim().is_empty() => name.trim().to_string(),
+        _ => host_from_addr(addr).to_string(),
+    };
+    ServerName::try_from(name.clone()).map_err(|_| anyhow!("Invalid TLS server name `{name}`"))
+}
+
+fn host_from_addr(addr: &str) -> &str {
+    if let Some(rest) = addr.strip_prefix('[') {
+        return rest.split(']').next().unwrap_or(rest);
+    }
+    match addr.rsplit_once(':') {
+        Some((host, _port)) => host,
+        None => addr,
+    }
+}
+
+fn build_tls_config() -> Result<ClientConfig> {
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let builder = ClientConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()?;
+
+    let roots = load_root_store()?;
+    let builder = match load_pinned_fingerprint()? {
+        Some(pin) => {
+            // With a pin and no custom CA the pin alone is trusted, which is what
+            // lets a self-signed HiveCore certificate be used.
+            let inner = match roots {
+                Some(roots) => Some(
+                    WebPkiServerVerifier::builder_with_provider(Arc::new(roots), provider.clone())
+                        .build()?,
+                ),
+                None => None,
+            };
+            builder
+                .dangerous()
+                .with_custom_certificate_verifier(Arc::new(PinnedServerVerifier {
+                    pin,
+                    inner,
+                    provider,
+                }))
+        }
+        None => builder.with_root_certificates(roots.unwrap_or_else(default_root_store)),
+    };
+
+    match load_client_identity()? {
+        Some((certs, key)) => Ok(builder
+            .with_client_auth_cert(certs, key)
+            .context("Invalid HiveNode client certificate")?),
+        None => Ok(builder.with_no_client_auth()),
+    }
+}
+
+fn default_root_store() -> RootCertStore {
+    RootCertStore {
+        roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+    }
+}
+
+/// Loads `HIVE_TLS_CA_FILE` as the only trusted roots, when set.
+fn load_root_store() -> Result<Option<RootCertStore>> {
+    let path = match env::var("HIVE_TLS_CA_FILE") {
+        Ok(path) if !path.trim().is_empty() => path,
+        _ => return Ok(None),
+    };
+
+    let mut roots = RootCertStore::empty();
+    for cert in read_certificates(&path)? {
+        roots
+            .add(cert)
+            .with_context(|| format!("Invalid CA certificate in {path}"))?;
+    }
+    if roots.is_empty() {
+        return Err(anyhow!("HIVE_TLS_CA_FILE {path} contains no certificates"));
+    }
+    Ok(Some(roots))
+}
+
+fn load_pinned_fingerprint() -> Result<Option<Vec<u8>>> {
+    match env::var("HIVE_TLS_PIN_SHA256") {
+        Ok(pin) if !pin.trim().is_empty() => parse_fingerprint(&pin).map(Some),
+        _ => Ok(None),
+    }
+}
+
+fn load_client_identity() -> Result<Option<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>)>>
+{
+    let cert_path = env::var("HIVE_TLS_CLIENT_CERT").ok();
+    let key_path = env::var("HIVE_TLS_CLIENT_KEY").ok();
+    match (cert_path, key_path) {
+        (None, None) => Ok(None),
+        (Some(cert_path), Some(key_path)) => {
+            let certs = read_certificates(&cert_path)?;
+            let mut reader = BufReader::new(
+                File::open(&key_path).with_context(|| format!("Failed to open {key_path}"))?,
+            );
+            let key = rustls_pemfile::private_key(&mut reader)?
+                .ok_or_else(|| anyhow!("No private key found in {key_path}"))?;
+            Ok(Some((certs, key)))
+        }
+        _ => Err(anyhow!(
+            "HIVE_TLS_CLIENT_CERT and HIVE_TLS_CLIENT_KEY must be set together"
+        )),
+    }
+}
+
+fn read_certificates(path: &str) -> Result<Vec<CertificateDer<'static>>> {
+    let mut reader =
+        BufReader::new(File::open(path).with_context(|| format!("Failed to open {path}"))?);
+    rustls_pemfile::certs(&mut reader)
+        .collect::<io::Result<Vec<_>>>()
+        .with_context(|| format!("Failed to read certificates from {path}"))
+}
+
+/// Parses a SHA-256 fingerprint written as hex, with or without `:` separators.
+fn parse_fingerprint(raw: &str) -> Result<Vec<u8>> {
+    let cleaned: String = raw
+        .trim()
+        .trim_start_matches("sha256:")
+        .chars()
+        .filter(|c| *c != ':')
+        .collect();
+    let bytes = hex::decode(&cleaned).context("HIVE_TLS_PIN_SHA256 must be hex encoded")?;
+    if bytes.len() != 32 {
+        return Err(anyhow!("HIVE_TLS_PIN_SHA256 must be a SHA-256 fingerprint"));
+    }
+    Ok(bytes)
+}
+
+#[derive(Debug)]
+struct PinnedServerVerifier {
+    pin: Vec<u8>,
+    inner: Option<Arc<WebPkiServerVerifier>>,
+    provider: Arc<CryptoProvider>,
+}
+
+impl ServerCertVerifier for PinnedServerVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let fingerprint = Sha256::digest(end_entity.as_ref());
+        if fingerprint.as_slice() != self.pin.as_slice() {
+            return Err(rustls::Error::General(format!(
+                "HiveCore certificate fingerprint {} does not match HIVE_TLS_PIN_SHA256",
+                hex::encode(fingerprint)
+            )));
+        }
+
+        if let Some(inner) = &self.inner {
+            inner.verify_server_cert(end_entity, intermediates, server_name, ocsp_response, now)?;
+        }
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{host_from_addr, parse_fingerprint};
+
+    #[test]
+    fn extracts_host_for_tls_server_name() {
+        assert_eq!(
+            host_from_addr("hivecore.example.com:7777"),
+            "hivecore.example.com"
+        );
+        assert_eq!(host_from_addr("[::1]:7777"), "::1");
+    }
+
+    #[test]
+    fn parses_colon_separated_fingerprints() {
+        let pin = "AB:".repeat(31) + "AB";
+        assert_eq!(parse_fingerprint(&pin).unwrap(), vec![0xab; 32]);
+        assert!(parse_fingerprint("abcd").is_err());
+    }
+}